@@ -9,7 +9,8 @@ use tracing::{debug, info};
 use vfs::FileId;
 
 use crate::hayroll_ds::{
-    extract_hayroll_seeds_from_syntax_roots, CodeRegion, HayrollMeta, HayrollSeed,
+    extract_hayroll_seeds_from_syntax_roots, log_hayroll_diagnostics, CodeRegion, HayrollMeta,
+    HayrollSeed,
 };
 use crate::util::{apply_source_change, collect_syntax_roots_from_db, SourceChangeBuilderSet};
 
@@ -28,7 +29,8 @@ pub fn run(workspace_path: &Path) -> Result<()> {
     {
         let syntax_roots: HashMap<FileId, SourceFile> = collect_syntax_roots_from_db(&db);
         let mut builder_set = SourceChangeBuilderSet::from_syntax_roots(&syntax_roots);
-        let hayroll_seeds = extract_hayroll_seeds_from_syntax_roots(&syntax_roots);
+        let (hayroll_seeds, seed_diagnostics) = extract_hayroll_seeds_from_syntax_roots(&syntax_roots);
+        log_hayroll_diagnostics(&vfs, &seed_diagnostics);
         let seeds_by_file =
             group_and_order_seeds(hayroll_seeds, |seed| matches!(seed, HayrollSeed::Expr(_)));
         let total_expr: usize = seeds_by_file.values().map(|seeds| seeds.len()).sum();
@@ -36,14 +38,15 @@ pub fn run(workspace_path: &Path) -> Result<()> {
 
         apply_expr_seed_edits(&mut builder_set, &syntax_roots, seeds_by_file);
 
-        let source_change = builder_set.finish();
+        let (source_change, _provenance) = builder_set.finish();
         apply_source_change(&mut db, &source_change);
     }
 
     // Pass 2: remove statement seeds by deleting the begin/end tag statements precisely.
     let syntax_roots: HashMap<FileId, SourceFile> = collect_syntax_roots_from_db(&db);
     let mut builder_set = SourceChangeBuilderSet::from_syntax_roots(&syntax_roots);
-    let hayroll_seeds = extract_hayroll_seeds_from_syntax_roots(&syntax_roots);
+    let (hayroll_seeds, seed_diagnostics) = extract_hayroll_seeds_from_syntax_roots(&syntax_roots);
+    log_hayroll_diagnostics(&vfs, &seed_diagnostics);
 
     for seed in hayroll_seeds.into_iter() {
         let HayrollSeed::Stmts(..) = seed else {
@@ -64,7 +67,7 @@ pub fn run(workspace_path: &Path) -> Result<()> {
         builder_set.add_file_edits(file_id, editor);
     }
 
-    let source_change = builder_set.finish();
+    let (source_change, _provenance) = builder_set.finish();
     apply_source_change(&mut db, &source_change);
 
     for file_id in syntax_roots.keys() {
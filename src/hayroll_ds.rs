@@ -1,11 +1,14 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
 use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 
+use serde::{Deserialize, Serialize};
 use serde_json::{self};
 use syntax::syntax_editor::Element;
 use syntax::{
     ast::{self, edit_in_place::AttrsOwnerEdit, HasAttrs},
-    syntax_editor::Position,
+    syntax_editor::{Position, SyntaxEditor},
     ted::{self},
     AstNode, AstToken, SourceFile, SyntaxElement, SyntaxNode,
 };
@@ -14,14 +17,129 @@ use vfs::FileId;
 
 use crate::util::*;
 
+// The two seed kinds a C2Rust-emitted tag can claim to be; mirrors the "invocation"/"conditional"
+// strings written into `seedType` by the instrumentation pass.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SeedType {
+    Invocation,
+    Conditional,
+}
+
+impl std::fmt::Display for SeedType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SeedType::Invocation => write!(f, "invocation"),
+            SeedType::Conditional => write!(f, "conditional"),
+        }
+    }
+}
+
+// The shape of the region a tag delimits; mirrors the `astKind` string written into the tag by
+// the instrumentation pass.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AstKind {
+    Expr,
+    Stmt,
+    Stmts,
+    Decl,
+    Decls,
+}
+
+impl std::fmt::Display for AstKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AstKind::Expr => write!(f, "Expr"),
+            AstKind::Stmt => write!(f, "Stmt"),
+            AstKind::Stmts => write!(f, "Stmts"),
+            AstKind::Decl => write!(f, "Decl"),
+            AstKind::Decls => write!(f, "Decls"),
+        }
+    }
+}
+
+// How a variadic argument occurrence is consumed where it's tagged in the body; mirrors the
+// `argUsage` string written into the tag by the instrumentation pass when it recognizes the
+// occurrence isn't a plain value spelling. Irrelevant (and omitted) for a non-variadic argument,
+// which is always `Value`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum ArgUsage {
+    #[default]
+    Value,
+    Count,
+    Ignore,
+}
+
+// Typed shape of the JSON payload embedded in a Hayroll tag's byte-string literal. Replaces raw
+// `serde_json::Value` indexing in `HayrollMeta` with a single fallible parse at construction time
+// (see `HayrollTag::parse`), so a malformed or missing field surfaces once, there, as a
+// `Result::Err` a caller can turn into a `HayrollDiagnostic`, instead of panicking the first time
+// some later accessor indexes the field it's missing. Fields that aren't meaningful for every
+// `astKind`/`seedType` combination (e.g. `isArg` on a Decls region) default rather than require,
+// matching how the instrumentation pass already omits them in those cases.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HayrollTagData {
+    pub hayroll: bool,
+    pub seed_type: SeedType,
+    #[serde(default)]
+    pub is_arg: bool,
+    pub name: String,
+    #[serde(default)]
+    pub arg_names: Vec<String>,
+    pub loc_begin: String,
+    pub loc_end: String,
+    pub cu_ln_col_begin: String,
+    pub cu_ln_col_end: String,
+    pub loc_ref_begin: String,
+    #[serde(default)]
+    pub loc_inv: String,
+    #[serde(default)]
+    pub loc_decl: String,
+    #[serde(default)]
+    pub can_be_fn: bool,
+    #[serde(default)]
+    pub is_variadic: bool,
+    #[serde(default)]
+    pub arg_usage: ArgUsage,
+    #[serde(default)]
+    pub is_lvalue: bool,
+    pub ast_kind: AstKind,
+    pub begin: bool,
+    #[serde(default)]
+    pub is_placeholder: bool,
+    #[serde(default)]
+    pub premise: String,
+    #[serde(default)]
+    pub merged_variants: Vec<String>,
+}
+
 // HayrollTag literal in the source code
 #[derive(Clone, Debug)]
 pub struct HayrollTag {
     pub literal: ast::Literal,
-    pub tag: serde_json::Value,
+    pub tag: HayrollTagData,
     pub file_id: FileId,
 }
 
+impl HayrollTag {
+    // Parses `content` (the decoded, trailing-NUL-trimmed byte-string payload of `literal`) into
+    // a `HayrollTagData` and pairs it with its originating literal. Callers are expected to have
+    // already confirmed `content` is meant to be a Hayroll tag (e.g. via a lenient `"hayroll":
+    // true` probe) before calling this, since a field missing here is treated as a genuine
+    // malformed tag rather than "not a tag at all".
+    pub fn parse(content: &str, literal: ast::Literal, file_id: FileId) -> Result<HayrollTag, String> {
+        let tag: HayrollTagData =
+            serde_json::from_str(content).map_err(|e| format!("malformed Hayroll tag: {e}"))?;
+        Ok(HayrollTag {
+            literal,
+            tag,
+            file_id,
+        })
+    }
+}
+
 // Intermediate trait: any type that can expose an underlying HayrollTag
 // Implementing this automatically grants a HayrollMeta implementation via the blanket impl below.
 pub trait HasHayrollTag {
@@ -49,6 +167,8 @@ pub trait HayrollMeta {
     fn cu_ln_col_end(&self) -> String;
     fn loc_ref_begin(&self) -> String;
     fn can_be_fn(&self) -> bool;
+    fn is_variadic(&self) -> bool;
+    fn arg_usage(&self) -> ArgUsage;
     fn file_id(&self) -> FileId;
     fn is_lvalue(&self) -> bool;
     fn ast_kind(&self) -> String;
@@ -67,122 +187,87 @@ pub trait HayrollMeta {
 
 impl<T: HasHayrollTag> HayrollMeta for T {
     fn seed_type(&self) -> String {
-        self.hayroll_tag().tag["seedType"]
-            .as_str()
-            .unwrap()
-            .to_string()
+        self.hayroll_tag().tag.seed_type.to_string()
     }
     fn is_invocation(&self) -> bool {
-        self.seed_type() == "invocation"
+        self.hayroll_tag().tag.seed_type == SeedType::Invocation
     }
     fn is_conditional(&self) -> bool {
-        self.seed_type() == "conditional"
+        self.hayroll_tag().tag.seed_type == SeedType::Conditional
     }
     fn is_arg(&self) -> bool {
-        self.hayroll_tag().tag["isArg"] == true
+        self.hayroll_tag().tag.is_arg
     }
     fn name(&self) -> String {
-        self.hayroll_tag().tag["name"].as_str().unwrap().to_string()
+        self.hayroll_tag().tag.name.clone()
     }
     fn arg_names(&self) -> Vec<String> {
-        self.hayroll_tag().tag["argNames"]
-            .as_array()
-            .unwrap()
-            .iter()
-            .map(|a| a.as_str().unwrap().to_string())
-            .collect()
+        self.hayroll_tag().tag.arg_names.clone()
     }
     fn loc_begin(&self) -> String {
-        self.hayroll_tag().tag["locBegin"]
-            .as_str()
-            .unwrap()
-            .to_string()
+        self.hayroll_tag().tag.loc_begin.clone()
     }
     fn loc_end(&self) -> String {
-        self.hayroll_tag().tag["locEnd"]
-            .as_str()
-            .unwrap()
-            .to_string()
+        self.hayroll_tag().tag.loc_end.clone()
     }
     fn cu_ln_col_begin(&self) -> String {
-        self.hayroll_tag().tag["cuLnColBegin"]
-            .as_str()
-            .unwrap()
-            .to_string()
+        self.hayroll_tag().tag.cu_ln_col_begin.clone()
     }
     fn cu_ln_col_end(&self) -> String {
-        self.hayroll_tag().tag["cuLnColEnd"]
-            .as_str()
-            .unwrap()
-            .to_string()
+        self.hayroll_tag().tag.cu_ln_col_end.clone()
     }
     fn loc_ref_begin(&self) -> String {
-        self.hayroll_tag().tag["locRefBegin"]
-            .as_str()
-            .unwrap()
-            .to_string()
+        self.hayroll_tag().tag.loc_ref_begin.clone()
     }
     fn can_be_fn(&self) -> bool {
-        self.hayroll_tag().tag["canBeFn"] == true
+        self.hayroll_tag().tag.can_be_fn
+    }
+    fn is_variadic(&self) -> bool {
+        self.hayroll_tag().tag.is_variadic
+    }
+    fn arg_usage(&self) -> ArgUsage {
+        self.hayroll_tag().tag.arg_usage
     }
     fn file_id(&self) -> FileId {
         self.hayroll_tag().file_id
     }
     fn is_lvalue(&self) -> bool {
-        self.hayroll_tag().tag["isLvalue"] == true
+        self.hayroll_tag().tag.is_lvalue
     }
     fn ast_kind(&self) -> String {
-        self.hayroll_tag().tag["astKind"]
-            .as_str()
-            .unwrap()
-            .to_string()
+        self.hayroll_tag().tag.ast_kind.to_string()
     }
     fn begin(&self) -> bool {
-        self.hayroll_tag().tag["begin"] == true
+        self.hayroll_tag().tag.begin
     }
     fn is_expr(&self) -> bool {
-        self.ast_kind() == "Expr"
+        self.hayroll_tag().tag.ast_kind == AstKind::Expr
     }
     fn is_stmt(&self) -> bool {
-        self.ast_kind() == "Stmt"
+        self.hayroll_tag().tag.ast_kind == AstKind::Stmt
     }
     fn is_stmts(&self) -> bool {
-        self.ast_kind() == "Stmts"
+        self.hayroll_tag().tag.ast_kind == AstKind::Stmts
     }
     fn is_decl(&self) -> bool {
-        self.ast_kind() == "Decl"
+        self.hayroll_tag().tag.ast_kind == AstKind::Decl
     }
     fn is_decls(&self) -> bool {
-        self.ast_kind() == "Decls"
+        self.hayroll_tag().tag.ast_kind == AstKind::Decls
     }
     fn is_placeholder(&self) -> bool {
-        self.hayroll_tag().tag["isPlaceholder"].as_bool().unwrap()
+        self.hayroll_tag().tag.is_placeholder
     }
     fn premise(&self) -> String {
-        self.hayroll_tag().tag["premise"]
-            .as_str()
-            .unwrap()
-            .to_string()
+        self.hayroll_tag().tag.premise.clone()
     }
     fn merged_variants(&self) -> Vec<String> {
-        self.hayroll_tag().tag["mergedVariants"]
-            .as_array()
-            .unwrap()
-            .iter()
-            .map(|a| a.as_str().unwrap().to_string())
-            .collect()
+        self.hayroll_tag().tag.merged_variants.clone()
     }
     fn with_appended_merged_variants(&self, new_variant: &str) -> ast::Literal {
         // Clone and update mergedVariants
         let mut new_tag = self.hayroll_tag().tag.clone();
-        let mut merged_variants = self.merged_variants();
-        merged_variants.push(new_variant.to_string());
-        new_tag["mergedVariants"] = serde_json::Value::Array(
-            merged_variants
-                .iter()
-                .map(|s| serde_json::Value::String(s.clone()))
-                .collect(),
-        );
+        new_tag.merged_variants.push(new_variant.to_string());
 
         // Serialize full JSON compactly
         let json = serde_json::to_string(&new_tag).unwrap();
@@ -198,7 +283,7 @@ impl<T: HasHayrollTag> HayrollMeta for T {
     fn with_updated_begin(&self, new_begin: bool) -> ast::Literal {
         // Clone and update begin
         let mut new_tag = self.hayroll_tag().tag.clone();
-        new_tag["begin"] = serde_json::Value::Bool(new_begin);
+        new_tag.begin = new_begin;
 
         // Serialize full JSON compactly
         let json = serde_json::to_string(&new_tag).unwrap();
@@ -240,6 +325,19 @@ impl HayrollSeed {
     // Returns immutable code region on the original AST
     // Useful for locating where to be replaced
     pub fn get_raw_code_region(&self, with_deref: bool) -> CodeRegion {
+        self.get_raw_code_region_with_index(with_deref, None)
+    }
+
+    // Same as `get_raw_code_region`, but lets a caller that's processing many seeds over the same
+    // `SourceFile` pass a pre-built `SrcLocIndex` so the `Decls` case can look up its items with
+    // two binary searches instead of `find_items_in_range`'s full rescan. Falls back to the linear
+    // scan when `index` is `None`, so existing callers (which all go through the plain
+    // `get_raw_code_region` above) are unaffected.
+    pub fn get_raw_code_region_with_index(
+        &self,
+        with_deref: bool,
+        index: Option<&SrcLocIndex>,
+    ) -> CodeRegion {
         match self {
             HayrollSeed::Expr(tag) => {
                 let if_expr = parent_until_kind::<ast::IfExpr>(&tag.literal).unwrap();
@@ -263,7 +361,7 @@ impl HayrollSeed {
                     .position(|s| s == stmt_begin)
                     .unwrap();
                 let end_idx = stmt_list.statements().position(|s| s == stmt_end).expect(&format!(
-                    "Could not find end stmt in stmt list for Hayroll tag: {}",
+                    "Could not find end stmt in stmt list for Hayroll tag: {:?}",
                     tag_end.tag
                 ));
                 CodeRegion::Stmts {
@@ -278,12 +376,94 @@ impl HayrollSeed {
                 let cu_loc_begin = LnCol::from_cu_ln_col(&self.cu_ln_col_begin());
                 let cu_loc_end = LnCol::from_cu_ln_col(&self.cu_ln_col_end());
                 let range = cu_loc_begin..=cu_loc_end;
+                let items = match index {
+                    Some(index) => index.items_in_range(&range),
+                    None => find_items_in_range(&source_file, range),
+                };
+                CodeRegion::Decls(items)
+            }
+        }
+    }
+
+    // Macro-aware counterpart of `get_raw_code_region`: when the tag literal's logical enclosure
+    // can't be found by climbing the raw syntax tree (the tag now sits inside a macro call's
+    // opaque token tree, having been nested inside another cluster's already-reconstructed macro
+    // by an earlier pass), climb through the macro's expansion instead via `sema`. Identical to
+    // `get_raw_code_region` for tags in real (non-expanded) source, and when `sema` is `None`.
+    pub fn get_raw_code_region_macro_aware(
+        &self,
+        with_deref: bool,
+        sema: Option<&hir::Semantics<'_, ide::RootDatabase>>,
+    ) -> CodeRegion {
+        match self {
+            HayrollSeed::Expr(tag) => {
+                let if_expr = parent_until_kind_macro_aware::<ast::IfExpr>(sema, &tag.literal).unwrap();
+                if with_deref && self.is_lvalue() {
+                    let star_expr = parent_until_kind_and_cond_macro_aware::<ast::PrefixExpr>(
+                        sema,
+                        &if_expr,
+                        |prefix_expr| prefix_expr.op_kind().unwrap() == ast::UnaryOp::Deref,
+                    )
+                    .unwrap();
+                    CodeRegion::Expr(star_expr.into())
+                } else {
+                    CodeRegion::Expr(if_expr.into())
+                }
+            }
+            HayrollSeed::Stmts(tag_begin, tag_end) => {
+                let stmt_begin = parent_until_kind_macro_aware::<ast::Stmt>(sema, &tag_begin.literal).unwrap();
+                let stmt_end = parent_until_kind_macro_aware::<ast::Stmt>(sema, &tag_end.literal).unwrap();
+                let stmt_list = parent_until_kind_macro_aware::<ast::StmtList>(sema, &stmt_begin).unwrap();
+                let start_idx = stmt_list
+                    .statements()
+                    .position(|s| s == stmt_begin)
+                    .unwrap();
+                let end_idx = stmt_list.statements().position(|s| s == stmt_end).expect(&format!(
+                    "Could not find end stmt in stmt list for Hayroll tag: {:?}",
+                    tag_end.tag
+                ));
+                CodeRegion::Stmts {
+                    parent: stmt_list,
+                    range: start_idx..=end_idx,
+                }
+            }
+            HayrollSeed::Decls(tag) => {
+                // Decl discovery works off line/column ranges in the enclosing source file
+                // rather than syntactic ancestry, so there's no macro-expansion boundary to
+                // climb through here.
+                let source_file = get_source_file(&tag.literal);
+                let cu_loc_begin = LnCol::from_cu_ln_col(&self.cu_ln_col_begin());
+                let cu_loc_end = LnCol::from_cu_ln_col(&self.cu_ln_col_end());
+                let range = cu_loc_begin..=cu_loc_end;
                 let items = find_items_in_range(&source_file, range);
                 CodeRegion::Decls(items)
             }
         }
     }
 
+    // Macro-aware counterpart of `get_raw_code_region_inside_tag`.
+    pub fn get_raw_code_region_inside_tag_macro_aware(
+        &self,
+        sema: Option<&hir::Semantics<'_, ide::RootDatabase>>,
+    ) -> CodeRegion {
+        let region = self.get_raw_code_region_macro_aware(false, sema);
+        match region {
+            CodeRegion::Expr(expr) => {
+                let if_expr = ast::IfExpr::cast(expr.syntax().clone()).unwrap();
+                CodeRegion::Expr(if_expr.then_branch().unwrap().into())
+            }
+            CodeRegion::Stmts { parent, range } => {
+                let new_start = range.start() + 1;
+                let new_end = range.end() - 1;
+                CodeRegion::Stmts {
+                    parent,
+                    range: new_start..=new_end,
+                }
+            }
+            CodeRegion::Decls(_) => region,
+        }
+    }
+
     // Returns immutable code region on the original AST, not including the hayroll tag itself
     pub fn get_raw_code_region_inside_tag(&self) -> CodeRegion {
         let region = self.get_raw_code_region(false);
@@ -354,6 +534,44 @@ impl HayrollSeed {
         }
     }
 
+    // Semantic fallback for `ptr_or_base_type`/`base_type`, used where the `0 as *mut T`
+    // placeholder the instrumentation leaves in the else-branch can't be found syntactically
+    // (e.g. a nested seed now sitting inside a macro call's token tree). Queries the type of the
+    // if-expression's then-branch tail expression through `sema` instead of pattern-matching the
+    // else branch. Since that tail expression has the placeholder's pointer type for lvalue seeds
+    // (`*mut T`) and is the plain value for rvalue seeds, one query covers both cases, the same way
+    // `ptr_or_base_type` does.
+    pub fn semantic_type(&self, sema: &hir::Semantics<'_, ide::RootDatabase>) -> Option<ast::Type> {
+        let CodeRegion::Expr(expr) = self.get_raw_code_region(false) else {
+            return None;
+        };
+        let if_expr = ast::IfExpr::cast(expr.syntax().clone())?;
+        let tail_expr = if_expr.then_branch()?.stmt_list()?.tail_expr()?;
+        let ty = sema.type_of_expr(&tail_expr)?.original;
+        Some(ast_from_text::<ast::Type>(&format!(
+            "type T = {};",
+            ty.display(sema.db)
+        )))
+    }
+
+    // Which `macro_rules!` fragment specifier matches this argument's peeled syntax, so the
+    // generated pattern actually captures what callers pass instead of always demanding
+    // `expr`/`stmt`. Checked in order of specificity: a bare identifier, a literal, a path
+    // (a path expression, not a call), a type, a block, then falling back to a full expression.
+    pub fn macro_fragment_specifier(&self) -> &'static str {
+        match self {
+            HayrollSeed::Decls(_) => panic!("Decls not supported as macro arg"),
+            HayrollSeed::Stmts(_, _) => "stmt",
+            HayrollSeed::Expr(_) => {
+                let region = self.get_raw_code_region(true).peel_tag();
+                let CodeRegion::Expr(expr) = region else {
+                    unreachable!("Expr seed always yields a CodeRegion::Expr")
+                };
+                fragment_specifier_for_expr(&expr)
+            }
+        }
+    }
+
     pub fn is_structurally_compatible_with(&self, other: &Self) -> bool {
         match (self, other) {
             (HayrollSeed::Expr(_), HayrollSeed::Expr(_)) => true,
@@ -372,6 +590,48 @@ impl HayrollSeed {
                 _ => true,
             }
     }
+
+    // Semantic counterpart to `is_type_compatible_with`: when `sema` is available, resolves each
+    // seed's then-branch tail expression to a `hir::Type` (the same lookup `semantic_type` already
+    // does) and compares those through rust-analyzer's type-unification relation instead of
+    // `base_type()`'s textual rendering, so e.g. `i32` vs `int32_t`, a type alias, or a pointer
+    // that's merely spelled differently from its partner are recognized as compatible rather than
+    // rejected on a string mismatch. Falls back to the plain string comparison when `sema` is
+    // `None` or either side's semantic type can't be resolved (e.g. the seed's placeholder now
+    // sits inside a macro's opaque token tree). Callers that don't yet have a `Semantics` handy
+    // can keep calling `is_type_compatible_with` unchanged; this is purely additive.
+    pub fn is_type_compatible_with_sema(
+        &self,
+        other: &Self,
+        sema: Option<&hir::Semantics<'_, ide::RootDatabase>>,
+    ) -> bool {
+        if !self.is_structurally_compatible_with(other) {
+            return false;
+        }
+        let (HayrollSeed::Expr(_), HayrollSeed::Expr(_)) = (self, other) else {
+            return true;
+        };
+        if let Some(sema) = sema {
+            if let (Some(self_ty), Some(other_ty)) =
+                (self.semantic_hir_type(sema), other.semantic_hir_type(sema))
+            {
+                return self_ty.could_unify_with(sema.db, &other_ty);
+            }
+        }
+        self.base_type().unwrap().to_string() == other.base_type().unwrap().to_string()
+    }
+
+    // The `hir::Type` analogue of `semantic_type`: resolves the if-expression's then-branch tail
+    // expression (a `*mut T` pointer for lvalue seeds, the plain value otherwise) directly to a
+    // `hir::Type` instead of re-parsing its display string back into `ast::Type` syntax.
+    fn semantic_hir_type(&self, sema: &hir::Semantics<'_, ide::RootDatabase>) -> Option<hir::Type> {
+        let CodeRegion::Expr(expr) = self.get_raw_code_region(false) else {
+            return None;
+        };
+        let if_expr = ast::IfExpr::cast(expr.syntax().clone())?;
+        let tail_expr = if_expr.then_branch()?.stmt_list()?.tail_expr()?;
+        Some(sema.type_of_expr(&tail_expr)?.original)
+    }
 }
 
 // A CodeRegion can be either a single expression, a span of statements,
@@ -508,59 +768,99 @@ impl CodeRegion {
         }
     }
 
+    // Collects insert/replace/delete operations staged against this region's `lub()` into a
+    // single `SyntaxEditor` and applies them atomically in one `finish()`, instead of each caller
+    // hand-rolling its own `TreeMutator`/`ted::replace`/`clone_subtree` dance. Elements passed to
+    // the editor inside `f` are nodes of `self` itself (everything under `lub()`), so no
+    // intermediate mutable clone is needed before staging edits -- `finish()` is the only clone,
+    // however many edits `f` stages, which is what lets a caller compose several rewrites on the
+    // same region without earlier steps' positions going stale partway through.
+    // Returns the freshly edited tree's root, detached from the original.
+    pub fn edit(&self, f: impl FnOnce(&mut SyntaxEditor)) -> SyntaxNode {
+        let mut editor = SyntaxEditor::new(self.lub());
+        f(&mut editor);
+        editor.finish().new_root().clone()
+    }
+
+    // Detached copy of an `Expr`/`Stmts` region via a no-op `edit()`, for callers whose transform
+    // only changes the `Decls` variant but still need to hand back *some* detached copy of the
+    // other two. Not meaningful for `Decls`: its items are scattered, unrelated nodes destined to
+    // be spliced back in individually by the caller, not a single subtree `edit()` can rewrite as
+    // one unit.
+    fn detach(&self) -> CodeRegion {
+        let new_root = self.edit(|_editor| {});
+        match self {
+            CodeRegion::Expr(_) => CodeRegion::Expr(ast::Expr::cast(new_root).unwrap()),
+            CodeRegion::Stmts { range, .. } => CodeRegion::Stmts {
+                parent: ast::StmtList::cast(new_root).unwrap(),
+                range: range.clone(),
+            },
+            CodeRegion::Decls(_) => unreachable!("detach() only supports Expr/Stmts regions"),
+        }
+    }
+
     // Peels tag from expr or stmts, does nothing for decls
     // The CodeRegion must align with that generated from HayrollSeed::get_raw_code_region
     // Returns immutable CodeRegion that is no longer part of the original syntax tree
     pub fn peel_tag(&self) -> CodeRegion {
-        let mutator = ide_db::source_change::TreeMutator::new(&self.lub());
-        let mut_region = match self {
+        match self {
             CodeRegion::Expr(expr) => {
-                if let Some(if_expr) = ast::IfExpr::cast(expr.syntax().clone()) {
-                    let then_branch = if_expr.then_branch().unwrap();
-                    let then_branch_mut = mutator.make_mut(&then_branch);
-                    CodeRegion::Expr(then_branch_mut.into())
-                } else {
-                    let star_expr = ast::PrefixExpr::cast(expr.syntax().clone()).unwrap();
-                    let star_expr_mut = mutator.make_mut(&star_expr);
-                    let mut if_or_paren_expr = star_expr.expr().unwrap();
-                    while let Some(paren_expr) =
-                        ast::ParenExpr::cast(if_or_paren_expr.syntax().clone())
-                    {
-                        if_or_paren_expr = paren_expr.expr().unwrap();
-                    }
-                    let if_expr = ast::IfExpr::cast(if_or_paren_expr.syntax().clone()).unwrap();
-                    let then_branch = if_expr.then_branch().unwrap();
-                    let if_expr_mut = mutator.make_mut(&if_expr);
-                    let then_branch_mut = mutator.make_mut(&then_branch);
-                    ted::replace(if_expr_mut.syntax(), then_branch_mut.syntax());
-                    CodeRegion::Expr(star_expr_mut.into())
-                }
+                let (if_expr, then_branch) =
+                    if let Some(if_expr) = ast::IfExpr::cast(expr.syntax().clone()) {
+                        let then_branch = if_expr.then_branch().unwrap();
+                        (if_expr, then_branch)
+                    } else {
+                        let star_expr = ast::PrefixExpr::cast(expr.syntax().clone()).unwrap();
+                        let mut if_or_paren_expr = star_expr.expr().unwrap();
+                        while let Some(paren_expr) =
+                            ast::ParenExpr::cast(if_or_paren_expr.syntax().clone())
+                        {
+                            if_or_paren_expr = paren_expr.expr().unwrap();
+                        }
+                        let if_expr = ast::IfExpr::cast(if_or_paren_expr.syntax().clone()).unwrap();
+                        let then_branch = if_expr.then_branch().unwrap();
+                        (if_expr, then_branch)
+                    };
+                // For the bare-if-expr case `if_expr` is the region's own `lub()`, so this
+                // replaces the edited tree's root outright; for the star-expr case it replaces a
+                // descendant, leaving the dereference wrapped around the new root.
+                let new_root = self.edit(|editor| {
+                    editor.replace(if_expr.syntax(), then_branch.syntax());
+                });
+                CodeRegion::Expr(ast::Expr::cast(new_root).unwrap())
             }
-            CodeRegion::Stmts { parent, range } => {
-                let parent_mut = mutator.make_mut(parent);
+            CodeRegion::Stmts { range, .. } => {
+                let new_root = self.edit(|_editor| {});
+                let parent = ast::StmtList::cast(new_root).unwrap();
                 let start = *range.start();
                 let end = *range.end();
                 let new_start = if start == end { start } else { start + 1 };
                 let new_end = if end == 0 { 0 } else { end - 1 };
                 CodeRegion::Stmts {
-                    parent: parent_mut,
+                    parent,
                     range: new_start..=new_end,
                 }
             }
-            CodeRegion::Decls(_) => self.make_mut_with_mutator(&mutator),
-        };
-        mut_region.clone_subtree()
+            CodeRegion::Decls(_) => {
+                let mutator = ide_db::source_change::TreeMutator::new(&self.lub());
+                self.make_mut_with_mutator(&mutator).clone_subtree()
+            }
+        }
     }
 
     // Give every decls item that is scoped in a `extern "C"` its own unique scope
     // Expr and stmts stay the same
     // Returns immutable CodeRegion that is no longer part of the original syntax tree
     pub fn individualize_decls(&self) -> CodeRegion {
-        let mutator = ide_db::source_change::TreeMutator::new(&self.lub());
-        let mut_region = match self {
-            CodeRegion::Expr(_) => self.make_mut_with_mutator(&mutator),
-            CodeRegion::Stmts { .. } => self.make_mut_with_mutator(&mutator),
+        match self {
+            CodeRegion::Expr(_) => self.detach(),
+            CodeRegion::Stmts { .. } => self.detach(),
             CodeRegion::Decls(items) => {
+                // Each item here is a standalone node the caller will splice in individually at
+                // its own original position elsewhere, not a batch of edits against one shared
+                // tree, so this keeps building detached replacement nodes directly rather than
+                // going through `edit()`.
+                let mutator = ide_db::source_change::TreeMutator::new(&self.lub());
                 let items_processed: Vec<ast::Item> = items
                     .into_iter()
                     .map(|item| {
@@ -583,27 +883,25 @@ impl CodeRegion {
                         }
                     })
                     .collect::<Vec<_>>();
-                CodeRegion::Decls(items_processed)
+                CodeRegion::Decls(items_processed).clone_subtree()
             }
-        };
-        mut_region.clone_subtree()
+        }
     }
 
     // Peel off any #[c2rust::src_loc = "..."] attributes in the code region (only decls could have these)
     // Returns immutable CodeRegion that is no longer part of the original syntax tree
     pub fn peel_c2rust_src_locs(&self) -> CodeRegion {
-        let region = match self {
-            CodeRegion::Expr(_) => self.clone(),
-            CodeRegion::Stmts { .. } => self.clone(),
+        match self {
+            CodeRegion::Expr(_) => self.detach(),
+            CodeRegion::Stmts { .. } => self.detach(),
             CodeRegion::Decls(items) => {
                 let items_processed: Vec<ast::Item> = items
                     .into_iter()
                     .map(|item| peel_c2rust_src_locs_from_item(&item))
                     .collect::<Vec<_>>();
-                CodeRegion::Decls(items_processed)
+                CodeRegion::Decls(items_processed).clone_subtree()
             }
-        };
-        region.clone_subtree()
+        }
     }
 
     // Returns a range of syntax elements that represent the code region.
@@ -692,6 +990,123 @@ impl std::fmt::Display for CodeRegion {
     }
 }
 
+// Replace every standalone occurrence of identifier `name` in `text` with `replacement`. Scans
+// for identifier boundaries by hand instead of via a textual `replace` so that e.g. replacing `x`
+// doesn't also rewrite `xs` or `foo_x`; an occurrence written as a `$x` metavariable reference is
+// left as it is. `text` is the generated macro body string built by `macro_rules_arm`, not yet
+// reparsed, so this is plain string surgery rather than a syntax-tree edit.
+fn replace_whole_word(text: &str, name: &str, replacement: &str) -> String {
+    let is_ident_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i].is_ascii_alphabetic() || chars[i] == '_' {
+            let start = i;
+            while i < chars.len() && is_ident_char(chars[i]) {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            if word == name && !(start > 0 && chars[start - 1] == '$') {
+                out.push_str(replacement);
+            } else {
+                out.push_str(&word);
+            }
+        } else {
+            out.push(chars[i]);
+            i += 1;
+        }
+    }
+    out
+}
+
+// Like `replace_whole_word`, but matches a `'name` lifetime/loop-label token (the leading `'` plus
+// its identifier) instead of a bare identifier, so renaming a loop label doesn't also touch an
+// unrelated `'name` used as a type's lifetime parameter elsewhere in the same macro body.
+// `replacement` is the bare name to splice in after the `'`, not a full `'replacement` token.
+fn replace_whole_lifetime(text: &str, name: &str, replacement: &str) -> String {
+    let is_ident_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] == '\'' && i + 1 < chars.len() && is_ident_char(chars[i + 1]) {
+            let start = i + 1;
+            let mut j = start;
+            while j < chars.len() && is_ident_char(chars[j]) {
+                j += 1;
+            }
+            let word: String = chars[start..j].iter().collect();
+            out.push('\'');
+            out.push_str(if word == name { replacement } else { &word });
+            i = j;
+        } else {
+            out.push(chars[i]);
+            i += 1;
+        }
+    }
+    out
+}
+
+// Whether the not-yet-reparsed macro body text declares a `let <name>` or `let mut <name>`
+// binding -- a plain word scan mirroring `replace_whole_word`'s own identifier boundaries, since
+// `body` hasn't been turned back into a syntax tree yet at the point this is called.
+fn body_lets_bind_name(body: &str, name: &str) -> bool {
+    let is_ident_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let chars: Vec<char> = body.chars().collect();
+    let mut words: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i].is_ascii_alphabetic() || chars[i] == '_' {
+            let start = i;
+            while i < chars.len() && is_ident_char(chars[i]) {
+                i += 1;
+            }
+            words.push(chars[start..i].iter().collect());
+        } else {
+            i += 1;
+        }
+    }
+    words.windows(2).any(|w| w[0] == "let" && w[1] == name)
+        || words
+            .windows(3)
+            .any(|w| w[0] == "let" && w[1] == "mut" && w[2] == name)
+}
+
+// Whether the macro body text declares a loop label `'name:` -- checked textually for the same
+// reason as `body_lets_bind_name` above. Requiring the immediately-following `:` (rather than just
+// searching for `'name`) keeps this from matching an unrelated `'name` lifetime used as a type
+// parameter, which is never followed directly by a colon.
+fn body_declares_label(body: &str, name: &str) -> bool {
+    body.contains(&format!("'{name}:"))
+}
+
+// Readability pass, not a correctness fix (`macro_rules!` hygiene already makes a `$name`
+// reference and a same-spelled internal binding lexically distinct, unlike the C textual
+// substitution this pipeline reconstructs): renames any internal `let`/loop-label binding in
+// `body` spelled like a name in `reserved` to a fresh `__hayroll_N` name, so nothing reads like it
+// might shadow a `$name` of the same spelling. `next_id` keeps multiple renames in one call distinct.
+fn rename_colliding_internal_bindings(
+    body: &str,
+    reserved: &HashSet<String>,
+    next_id: &mut u32,
+) -> String {
+    let mut body = body.to_string();
+    for name in reserved {
+        if body_lets_bind_name(&body, name) {
+            let fresh = format!("__hayroll_{next_id}");
+            *next_id += 1;
+            body = replace_whole_word(&body, name, &fresh);
+        }
+        if body_declares_label(&body, name) {
+            let fresh = format!("hayroll_{next_id}");
+            *next_id += 1;
+            body = replace_whole_lifetime(&body, name, &fresh);
+        }
+    }
+    body
+}
+
 // HayrollMacroInv is a macro invocation in AST representation
 // It contains the CodeRegion of the expansion range and the CodeRegions of the arguments
 #[derive(Clone)]
@@ -707,6 +1122,69 @@ impl HasHayrollTag for HayrollMacroInv {
     }
 }
 
+// Spells a bound argument's text for a macro call site: comma-joined source text for every seed
+// bound to that name. A non-variadic argument always has exactly one bound seed, so this is the
+// same single-seed text as before for it; a variadic argument (its trailing seeds all sharing the
+// last declared name, per `variadic_arg_name`) has one seed per actual C call-site argument, and
+// all of them must be spelled out at the call site for the `$(...),*` repetition on the
+// `macro_rules!` side to bind the same arguments it did in the original C invocation.
+fn arg_regions_spelling(arg_regions: &[HayrollSeed]) -> String {
+    arg_regions
+        .iter()
+        .map(|seed| seed.get_raw_code_region(true).peel_tag().to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+// Names this cluster's macro body binds via `let`, as opposed to receiving them as `$name`
+// metavariables -- a `let` inside the body shadows any caller-scope meaning of the same name, so
+// it's never a hygiene capture candidate even if the identifier also exists in the caller's own
+// scope.
+pub(crate) fn hygiene_bound_names(region: &CodeRegion) -> HashSet<String> {
+    region
+        .syntax_element_vec()
+        .into_iter()
+        .filter_map(|element| match element {
+            syntax::NodeOrToken::Node(node) => Some(node),
+            syntax::NodeOrToken::Token(_) => None,
+        })
+        .flat_map(|node| node.descendants().filter_map(ast::LetStmt::cast))
+        .filter_map(|let_stmt| let_stmt.pat())
+        .flat_map(|pat| pat.syntax().descendants().filter_map(ast::IdentPat::cast))
+        .filter_map(|ident_pat| ident_pat.name())
+        .map(|name| name.to_string())
+        .collect()
+}
+
+// Bare (unqualified) paths referenced in `region` that are neither a declared macro parameter
+// (`param_names`) nor a name the body itself binds via `let` -- candidates for "the original C
+// macro's textual substitution relied on this name already being in scope at the call site",
+// the one thing `macro_rules!` hygiene would otherwise silently stop honoring.
+pub(crate) fn hygiene_free_name_paths(
+    region: &CodeRegion,
+    param_names: &HashSet<String>,
+) -> Vec<ast::Path> {
+    let bound = hygiene_bound_names(region);
+    region
+        .syntax_element_vec()
+        .into_iter()
+        .filter_map(|element| match element {
+            syntax::NodeOrToken::Node(node) => Some(node),
+            syntax::NodeOrToken::Token(_) => None,
+        })
+        .flat_map(|node| node.descendants().filter_map(ast::Path::cast))
+        .filter(|path| path.qualifier().is_none())
+        .filter(|path| {
+            path.segment()
+                .and_then(|segment| segment.name_ref())
+                .is_some_and(|name_ref| {
+                    let name = name_ref.to_string();
+                    !param_names.contains(&name) && !bound.contains(&name)
+                })
+        })
+        .collect()
+}
+
 impl HayrollMacroInv {
     pub fn signature(&self) -> String {
         if self.seed.is_decl() || self.seed.is_decls() {
@@ -728,12 +1206,17 @@ impl HayrollMacroInv {
         }
 
         for (_, arg_regions) in &self.args {
+            // A variadic arg the call site happened to pass zero trailing arguments for has no
+            // bound occurrence to read a type off of (see `macro_rules_arm`'s matching case for
+            // the repetition group itself); skip it rather than indexing into an empty Vec.
+            let Some(first_seed) = arg_regions.first() else {
+                continue;
+            };
             if arg_regions.iter().any(|seed| seed.is_stmt()) {
                 parts.push("stmt".to_string());
                 continue;
             }
 
-            let first_seed = arg_regions.first().unwrap();
             parts.push(sanitize(&first_seed.base_type().unwrap().to_string()));
         }
 
@@ -752,12 +1235,20 @@ impl HayrollMacroInv {
 
     // Replace the args tagged code regions into $argName, for generating macro definition
     // Returns immutable CodeRegion
+    //
+    // A non-variadic arg has exactly one bound region, but a variadic arg's `arg_regions` are all
+    // of the C call site's trailing arguments spelled out literally and comma-separated in the
+    // body (see `macro_rules_arm`), so for N >= 2 they're contiguous siblings, not N independent
+    // usages. Substituting each of them individually would paste `substitute`'s result once per
+    // bound argument (tripling a 3-argument `$($rest),*` group, say); instead, collapse the whole
+    // run of bound regions -- and the separator commas between them -- into a single replacement
+    // spanning from the first region to the last, substituted once off the first as representative.
     pub fn replace_arg_regions_into(
         &self,
         peel_tag: bool,
         return_inv_region_with_deref: bool,
         args_require_lvalue: &Vec<bool>,
-        substitute: fn(&HayrollSeed) -> Vec<SyntaxElement>,
+        substitute: impl Fn(&HayrollSeed) -> Vec<SyntaxElement>,
     ) -> CodeRegion {
         let mut delayed_tasks: Vec<Box<dyn FnOnce()>> = Vec::new();
         let region = self.seed.get_raw_code_region(return_inv_region_with_deref);
@@ -765,15 +1256,22 @@ impl HayrollMacroInv {
         let region_mut = region.make_mut_with_mutator(&mutator);
         for ((_, arg_regions), requires_lvalue) in self.args.iter().zip(args_require_lvalue.iter())
         {
-            for arg_region in arg_regions {
-                let arg_code_region = arg_region.get_raw_code_region(!requires_lvalue);
-                let arg_code_region_mut = arg_code_region.make_mut_with_mutator(&mutator);
-                let arg_code_region_range = arg_code_region_mut.syntax_element_range();
-                let new_tokens = substitute(arg_region);
-                delayed_tasks.push(Box::new(move || {
-                    ted::replace_all(arg_code_region_range, new_tokens);
-                }));
-            }
+            let Some(first_arg_region) = arg_regions.first() else {
+                continue;
+            };
+            let last_arg_region = arg_regions.last().unwrap();
+            let first_code_region_mut = first_arg_region
+                .get_raw_code_region(!requires_lvalue)
+                .make_mut_with_mutator(&mutator);
+            let last_code_region_mut = last_arg_region
+                .get_raw_code_region(!requires_lvalue)
+                .make_mut_with_mutator(&mutator);
+            let start = first_code_region_mut.syntax_element_range().start().clone();
+            let end = last_code_region_mut.syntax_element_range().end().clone();
+            let new_tokens = substitute(first_arg_region);
+            delayed_tasks.push(Box::new(move || {
+                ted::replace_all(start..=end, new_tokens);
+            }));
         }
         for task in delayed_tasks {
             task();
@@ -786,19 +1284,40 @@ impl HayrollMacroInv {
         }
     }
 
-    pub fn macro_rules(&self) -> ast::MacroRules {
-        let macro_name = self.name_with_signature();
-        // arg format: ($x:expr) or ($x:stmt)
+    // The variadic arg, if any, is the last declared parameter (mirroring C's __VA_ARGS__,
+    // which must trail the fixed-arity prefix). Returns its name.
+    fn variadic_arg_name(&self) -> Option<String> {
+        if !self.is_variadic() {
+            return None;
+        }
+        self.args.last().map(|(name, _)| name.clone())
+    }
+
+    // The `(pattern) => { body }` text for this invocation's shape, without the surrounding
+    // `macro_rules! name { ... }` wrapper, so callers can either wrap a single arm (`macro_rules`
+    // below) or stitch several arms from structurally distinct invocations into one macro
+    // (`HayrollMacroDB::merge_macro_rules_by_loc_decl`). The variadic arg's `arg_regions` are its
+    // tagged occurrences in the body, so zero, one, or many of them all just work: the pattern
+    // always binds `$($rest:...),*`, and `replace_arg_regions_into` collapses all of them into a
+    // single `$($rest),*` splice regardless of how many trailing arguments the call site bound.
+    fn macro_rules_arm(&self) -> (String, String) {
+        let variadic_name = self.variadic_arg_name();
+        // arg format: ($x:expr) or ($x:stmt); the variadic trailing arg becomes $($rest:expr),*
         let macro_args = self
             .args
             .iter()
             .map(|(arg_name, arg_regions)| {
-                let arg_type = match arg_regions[0] {
-                    HayrollSeed::Expr(_) => "expr",
-                    HayrollSeed::Stmts(_, _) => "stmt",
-                    HayrollSeed::Decls(_) => panic!("Decls not supported as macro arg"),
-                };
-                format!("${}:{}", arg_name, arg_type)
+                // Only the variadic arg can legitimately have zero bound occurrences (the call
+                // site passed no trailing arguments); there's nothing to read a fragment kind off
+                // of in that case, so fall back to the always-valid `expr` specifier.
+                let arg_type = arg_regions
+                    .first()
+                    .map_or("expr", |seed| seed.macro_fragment_specifier());
+                if variadic_name.as_deref() == Some(arg_name.as_str()) {
+                    format!("$(${}:{}),*", arg_name, arg_type)
+                } else {
+                    format!("${}:{}", arg_name, arg_type)
+                }
             })
             .collect::<Vec<String>>()
             .join(", ");
@@ -809,13 +1328,27 @@ impl HayrollMacroInv {
                 &vec![false; self.args.len()],
                 |arg_region| {
                     let name = arg_region.name();
-                    let name_token = ast::make::tokens::ident(&name);
-                    let name_node = name_token.parent().unwrap().clone_for_update();
-                    let dollar_token_mut = get_dollar_token_mut();
-                    vec![
-                        syntax::NodeOrToken::Token(dollar_token_mut),
-                        syntax::NodeOrToken::Node(name_node),
-                    ]
+                    if variadic_name.as_deref() == Some(name.as_str()) {
+                        // The instrumentation pass tags a variadic occurrence with how the C body
+                        // actually consumed it at that spot -- spelled out value by value (the
+                        // default), counted via an argument-counting idiom, or bound only for its
+                        // repetition/side effects and never read -- so mirror that back into the
+                        // matching `macro_rules!` metavariable-expression form instead of always
+                        // spelling `$($rest),*`.
+                        match arg_region.arg_usage() {
+                            ArgUsage::Count => get_count_meta_expr_tokens_mut(&name),
+                            ArgUsage::Ignore => get_ignore_meta_expr_tokens_mut(&name),
+                            ArgUsage::Value => get_variadic_repetition_tokens_mut(&name),
+                        }
+                    } else {
+                        let name_token = ast::make::tokens::ident(&name);
+                        let name_node = name_token.parent().unwrap().clone_for_update();
+                        let dollar_token_mut = get_dollar_token_mut();
+                        vec![
+                            syntax::NodeOrToken::Token(dollar_token_mut),
+                            syntax::NodeOrToken::Node(name_node),
+                        ]
+                    }
                 },
             ),
             HayrollSeed::Decls(_) => self
@@ -824,23 +1357,120 @@ impl HayrollMacroInv {
                 .individualize_decls()
                 .peel_c2rust_src_locs(),
         };
+        (macro_args, macro_body.to_string())
+    }
+
+    pub fn macro_rules(&self) -> ast::MacroRules {
+        let macro_name = self.name_with_signature();
+        let (macro_args, macro_body) = self.macro_rules_arm();
         let macro_def = format!(
             "macro_rules! {}\n{{\n    ({}) => {{\n    {}\n    }}\n}}",
             macro_name, macro_args, macro_body
         );
         let macro_rules_node = ast_from_text::<ast::MacroRules>(&macro_def);
-        macro_rules_node.clone_for_update()
+        prettify_generated_node(macro_rules_node).clone_for_update()
     }
 
     pub fn macro_call(&self) -> ast::MacroCall {
+        self.macro_call_with_name(&self.name_with_signature())
+    }
+
+    // Call-site counterpart of `HayrollMacroDB::merge_macro_rules_by_loc_decl`: several structurally
+    // distinct clusters sharing a declaration site are folded into one `macro_rules!`, so each of
+    // their invocations has to call it by that shared name instead of its own `name_with_signature()`.
+    pub fn macro_call_with_name(&self, macro_name: &str) -> ast::MacroCall {
+        let args_spelling: String = self
+            .args
+            .iter()
+            .map(|(_, arg_regions)| arg_regions_spelling(arg_regions))
+            .collect::<Vec<String>>()
+            .join(", ");
+        let macro_call = if self.is_expr() {
+            format!("{}!({})", macro_name, args_spelling)
+        } else {
+            format!("{}!({});", macro_name, args_spelling)
+        };
+        prettify_generated_node(ast_from_text::<ast::MacroCall>(&macro_call)).clone_for_update()
+    }
+
+    // Shared by `macro_rules_with_captures` and `macro_rules_with_crate_qualified_paths`: appends
+    // one `$name:ident` parameter per name in `captured` (promoting each bare occurrence in the
+    // body to a `$name` metavariable reference), then rewrites every bare occurrence of a name in
+    // `crate_qualified` to its (already `$crate::`-rooted) replacement text. The two rewrites
+    // target disjoint names -- a captured local and a crate item never share a name, since one
+    // resolves to `PathResolution::Local` and the other to `PathResolution::Def` -- so applying
+    // both in one pass is safe even when a cluster needs both at once.
+    pub fn macro_rules_with_hygiene(
+        &self,
+        captured: &BTreeSet<String>,
+        crate_qualified: &HashMap<String, String>,
+    ) -> ast::MacroRules {
+        let macro_name = self.name_with_signature();
+        let (mut macro_args, mut macro_body) = self.macro_rules_arm();
+        for name in captured {
+            if !macro_args.is_empty() {
+                macro_args.push_str(", ");
+            }
+            macro_args.push_str(&format!("${}:ident", name));
+            macro_body = replace_whole_word(&macro_body, name, &format!("${name}"));
+        }
+        for (name, qualified_path) in crate_qualified {
+            macro_body = replace_whole_word(&macro_body, name, qualified_path);
+        }
+        // See `rename_colliding_internal_bindings`: a readability safety net, not a correctness
+        // requirement, so it only targets the macro's own parameters and the names just promoted
+        // above -- a crate-qualified name is never `let`-bound internally to begin with (it
+        // resolved to `PathResolution::Def`, not `Local`), so it's not part of `reserved`.
+        let reserved: HashSet<String> = self
+            .param_names()
+            .into_iter()
+            .chain(captured.iter().cloned())
+            .collect();
+        let mut next_id = 0u32;
+        macro_body = rename_colliding_internal_bindings(&macro_body, &reserved, &mut next_id);
+        let macro_def = format!(
+            "macro_rules! {}\n{{\n    ({}) => {{\n    {}\n    }}\n}}",
+            macro_name, macro_args, macro_body
+        );
+        let macro_rules_node = ast_from_text::<ast::MacroRules>(&macro_def);
+        prettify_generated_node(macro_rules_node).clone_for_update()
+    }
+
+    // Same shape as `macro_rules`, but with one extra `$name:ident` parameter appended per name in
+    // `captured`, and every bare occurrence of that name in the body promoted to `$name` -- the
+    // promotion the hygiene pass in `reaper_core::run` applies when a name the macro body reads
+    // isn't one of its own declared arguments but a binding that's actually live at the call site.
+    // Plain C-style textual substitution captured such a name for free; `macro_rules!` hygiene
+    // does not, so it has to be threaded through explicitly instead. `captured` must line up with
+    // what `macro_call_with_captures` is given for every invocation in the cluster, since the
+    // parameter list and each call site's argument list have to match positionally.
+    pub fn macro_rules_with_captures(&self, captured: &BTreeSet<String>) -> ast::MacroRules {
+        self.macro_rules_with_hygiene(captured, &HashMap::new())
+    }
+
+    // Same shape as `macro_rules`, but every bare occurrence of a name in `crate_qualified` is
+    // rewritten to its `$crate::...`-rooted replacement -- applied when the body references an
+    // item declared elsewhere in the same crate, so the macro stays callable once lifted to
+    // `top_pos` regardless of what's in scope at any particular invocation site. Unlike a
+    // captured local, a crate item needs no new macro parameter: `$crate` always resolves inside
+    // a `macro_rules!` expansion, so the qualified path is baked into the body as-is.
+    pub fn macro_rules_with_crate_qualified_paths(
+        &self,
+        crate_qualified: &HashMap<String, String>,
+    ) -> ast::MacroRules {
+        self.macro_rules_with_hygiene(&BTreeSet::new(), crate_qualified)
+    }
+
+    // Call-site counterpart of `macro_rules_with_captures`: appends the literal identifier for
+    // each captured name, in the same order, so the call supplies the metavariable its matching
+    // macro_rules arm now expects.
+    pub fn macro_call_with_captures(&self, captured: &BTreeSet<String>) -> ast::MacroCall {
         let macro_name = self.name_with_signature();
         let args_spelling: String = self
             .args
             .iter()
-            .map(|(_, arg_regions)| {
-                let arg_code_region = arg_regions[0].get_raw_code_region(true).peel_tag();
-                arg_code_region.to_string()
-            })
+            .map(|(_, arg_regions)| arg_regions_spelling(arg_regions))
+            .chain(captured.iter().cloned())
             .collect::<Vec<String>>()
             .join(", ");
         let macro_call = if self.is_expr() {
@@ -848,11 +1478,19 @@ impl HayrollMacroInv {
         } else {
             format!("{}!({});", macro_name, args_spelling)
         };
-        ast_from_text::<ast::MacroCall>(&macro_call).clone_for_update()
+        prettify_generated_node(ast_from_text::<ast::MacroCall>(&macro_call)).clone_for_update()
     }
 
-    pub fn fn_(&self, args_require_lvalue: &Vec<bool>) -> ast::Fn {
-        let return_type: String = match self.seed.ptr_or_base_type() {
+    pub fn fn_(
+        &self,
+        args_require_lvalue: &Vec<bool>,
+        sema: Option<&hir::Semantics<'_, ide::RootDatabase>>,
+    ) -> ast::Fn {
+        let return_type: String = match self
+            .seed
+            .ptr_or_base_type()
+            .or_else(|| sema.and_then(|sema| self.seed.semantic_type(sema)))
+        {
             Some(t) => " -> ".to_string() + &t.to_string(),
             None => "".to_string(),
         };
@@ -866,9 +1504,15 @@ impl HayrollMacroInv {
                     None
                 } else {
                     let t = if *requires_lvalue {
-                        arg_regions[0].ptr_or_base_type().unwrap()
+                        arg_regions[0]
+                            .ptr_or_base_type()
+                            .or_else(|| sema.and_then(|sema| arg_regions[0].semantic_type(sema)))
+                            .unwrap()
                     } else {
-                        arg_regions[0].base_type().unwrap()
+                        arg_regions[0]
+                            .base_type()
+                            .or_else(|| sema.and_then(|sema| arg_regions[0].semantic_type(sema)))
+                            .unwrap()
                     };
                     Some(format!("{}: {}", arg_name, t))
                 }
@@ -889,11 +1533,18 @@ impl HayrollMacroInv {
             return_type,
             fn_body
         );
-        ast_from_text::<ast::Fn>(&fn_).clone_for_update()
+        prettify_generated_node(ast_from_text::<ast::Fn>(&fn_)).clone_for_update()
     }
 
     pub fn call_expr(&self, args_require_lvalue: &Vec<bool>) -> ast::Expr {
-        let fn_name = self.name_with_signature();
+        self.call_expr_with_name(&self.name_with_signature(), args_require_lvalue)
+    }
+
+    // Same as `call_expr`, but against an explicitly given function name instead of
+    // `name_with_signature()` -- needed when the callee isn't this invocation's own per-signature
+    // `fn_`, but a single generic `fn` shared across several differently-signatured clusters (see
+    // `unify_fn_clusters`), whose name carries no per-signature suffix at all.
+    pub fn call_expr_with_name(&self, fn_name: &str, args_require_lvalue: &Vec<bool>) -> ast::Expr {
         let args_spelling: String = self
             .args
             .iter()
@@ -915,11 +1566,21 @@ impl HayrollMacroInv {
         } else {
             call_expr
         };
-        expr_from_text(&call_expr).clone_for_update()
+        prettify_generated_node(expr_from_text(&call_expr)).clone_for_update()
     }
 
     pub fn call_expr_or_stmt_mut(&self, args_require_lvalue: &Vec<bool>) -> SyntaxNode {
-        let call_expr = self.call_expr(args_require_lvalue);
+        self.call_expr_or_stmt_mut_with_name(&self.name_with_signature(), args_require_lvalue)
+    }
+
+    // Same as `call_expr_or_stmt_mut`, but against an explicitly given function name -- see
+    // `call_expr_with_name`.
+    pub fn call_expr_or_stmt_mut_with_name(
+        &self,
+        fn_name: &str,
+        args_require_lvalue: &Vec<bool>,
+    ) -> SyntaxNode {
+        let call_expr = self.call_expr_with_name(fn_name, args_require_lvalue);
         if self.seed.is_expr() {
             call_expr.syntax().clone()
         } else {
@@ -937,12 +1598,15 @@ impl HayrollMacroInv {
         })
     }
 
-    pub fn args_internally_type_compatible(&self) -> bool {
+    pub fn args_internally_type_compatible(
+        &self,
+        sema: Option<&hir::Semantics<'_, ide::RootDatabase>>,
+    ) -> bool {
         self.args.iter().all(|(_, seeds)| {
             seeds.is_empty()
                 || seeds
                     .iter()
-                    .all(|seed| seed.is_type_compatible_with(&seeds[0]))
+                    .all(|seed| seed.is_type_compatible_with_sema(&seeds[0], sema))
         })
     }
 
@@ -966,10 +1630,14 @@ impl HayrollMacroInv {
                 })
     }
 
-    pub fn is_type_compatible_with(&self, other: &Self) -> bool {
-        self.seed.is_type_compatible_with(&other.seed)
-            && self.args_internally_type_compatible()
-            && other.args_internally_type_compatible()
+    pub fn is_type_compatible_with(
+        &self,
+        other: &Self,
+        sema: Option<&hir::Semantics<'_, ide::RootDatabase>>,
+    ) -> bool {
+        self.seed.is_type_compatible_with_sema(&other.seed, sema)
+            && self.args_internally_type_compatible(sema)
+            && other.args_internally_type_compatible(sema)
             && self.args.len() == other.args.len()
             && self
                 .args
@@ -981,7 +1649,7 @@ impl HayrollMacroInv {
                     } else if seeds1.is_empty() != seeds2.is_empty() {
                         false
                     } else {
-                        seeds1[0].is_type_compatible_with(&seeds2[0])
+                        seeds1[0].is_type_compatible_with_sema(&seeds2[0], sema)
                     }
                 })
     }
@@ -992,6 +1660,76 @@ impl HayrollMacroInv {
             .map(|(_, seeds)| seeds.is_empty() || seeds.iter().all(|seed| seed.is_lvalue()))
             .collect()
     }
+
+    // Whether every argument this invocation actually uses has a concrete Rust type we can
+    // spell in a `fn` signature. Stmt/Stmts/Decls-kind arguments have no base_type/ptr_or_base_type
+    // (they're spliced in as token trees, not typed expressions), so a `fn` signature can't be
+    // generated for them — only `macro_rules!` can take them as `stmt`/item fragments.
+    fn args_types_inferable(&self) -> bool {
+        self.args.iter().all(|(_, arg_regions)| {
+            arg_regions.is_empty() || {
+                let seed = &arg_regions[0];
+                if seed.is_lvalue() {
+                    seed.ptr_or_base_type().is_some()
+                } else {
+                    seed.base_type().is_some()
+                }
+            }
+        })
+    }
+
+    // The tag's own `canBeFn` is necessary but not sufficient: an lvalue-producing expansion
+    // can only be replayed faithfully as `*call(...)`, which isn't interchangeable with the
+    // macro form at every use site (e.g. `&MACRO(x)` vs `&*call(x)` differ once the call is
+    // no longer textually substituted), and a statement-context expansion may introduce
+    // bindings the surrounding scope depends on. Both must fall back to `macro_rules!`, as
+    // must any invocation whose argument types we can't spell in a signature, and any invocation
+    // that reads a caller-local capture (without `sema`, every free name `capturing_idents`
+    // finds counts as one) -- a capture can be
+    // threaded through `macro_rules_with_captures`'s extra `$name:ident` parameter, but a plain
+    // `fn` has no equivalent mechanism for implicitly seeing a caller's local variable.
+    pub fn can_be_fn(&self, sema: Option<&hir::Semantics<'_, ide::RootDatabase>>) -> bool {
+        self.hayroll_tag().tag.can_be_fn
+            && !self.is_lvalue()
+            && !self.is_stmts()
+            && self.args_types_inferable()
+            && self.capturing_idents(sema).is_empty()
+    }
+
+    // This invocation's own declared `$name` parameters -- never capture candidates, since they're
+    // always supplied explicitly at the call site rather than relied on being in caller scope.
+    pub fn param_names(&self) -> HashSet<String> {
+        self.args.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    // Free names this invocation's body reads that aren't one of its own `$name` parameters or a
+    // `let`-bound local of the body itself -- in other words, names `macro_rules!` hygiene would
+    // stop resolving to the caller's scope once the body stops being textually substituted.
+    // Without `sema` this is a purely syntactic over-approximation (every such free name);
+    // with `sema` it's narrowed to the ones that actually resolve to a local variable at this
+    // invocation's own call site, since a free name resolving to a `fn`/`const`/other item needs no
+    // promotion at all (`macro_rules!` lets item references cross the expansion boundary
+    // unhindered). `HayrollMacroInv::can_be_fn` uses this to rule the plain function form out
+    // whenever any such name is found, since a captured local can be threaded through a macro as
+    // an extra `$name:ident` parameter (as `macro_rules_with_captures` does) but a generated `fn`
+    // has no such mechanism.
+    pub fn capturing_idents(&self, sema: Option<&hir::Semantics<'_, ide::RootDatabase>>) -> Vec<String> {
+        let free_paths =
+            hygiene_free_name_paths(&self.seed.get_raw_code_region(true), &self.param_names());
+        let Some(sema) = sema else {
+            return free_paths
+                .iter()
+                .filter_map(|path| path.segment().and_then(|segment| segment.name_ref()))
+                .map(|name_ref| name_ref.to_string())
+                .collect();
+        };
+        free_paths
+            .iter()
+            .filter(|path| matches!(sema.resolve_path(path), Some(hir::PathResolution::Local(_))))
+            .filter_map(|path| path.segment().and_then(|segment| segment.name_ref()))
+            .map(|name_ref| name_ref.to_string())
+            .collect()
+    }
 }
 
 pub struct HayrollMacroCluster {
@@ -1005,8 +1743,9 @@ impl HasHayrollTag for HayrollMacroCluster {
 }
 
 impl HayrollMacroCluster {
-    pub fn can_be_fn(&self) -> bool {
-        self.invs_internally_type_compatible() && self.invocations.iter().all(|inv| inv.can_be_fn())
+    pub fn can_be_fn(&self, sema: Option<&hir::Semantics<'_, ide::RootDatabase>>) -> bool {
+        self.invs_internally_type_compatible(sema)
+            && self.invocations.iter().all(|inv| inv.can_be_fn(sema))
     }
 
     pub fn invs_internally_structurally_compatible(&self) -> bool {
@@ -1017,12 +1756,19 @@ impl HayrollMacroCluster {
             .all(|inv| inv.is_structurally_compatible_with(&first))
     }
 
-    pub fn invs_internally_type_compatible(&self) -> bool {
+    // `sema`, when available, lets two invocations whose arguments/seed are spelled differently
+    // but resolve to the same type (a type alias, `i32` vs `int32_t`, a pointer written through a
+    // typedef) cluster together instead of being rejected on `is_type_compatible_with`'s textual
+    // comparison alone -- see `HayrollSeed::is_type_compatible_with_sema`.
+    pub fn invs_internally_type_compatible(
+        &self,
+        sema: Option<&hir::Semantics<'_, ide::RootDatabase>>,
+    ) -> bool {
         assert!(!self.invocations.is_empty());
         let first = &self.invocations[0];
         self.invocations
             .iter()
-            .all(|inv| inv.is_type_compatible_with(&first))
+            .all(|inv| inv.is_type_compatible_with(&first, sema))
     }
 
     pub fn macro_rules(&self) -> ast::MacroRules {
@@ -1030,9 +1776,31 @@ impl HayrollMacroCluster {
         self.invocations[0].macro_rules()
     }
 
-    pub fn fn_(&self) -> ast::Fn {
-        assert!(self.invs_internally_type_compatible());
-        self.invocations[0].fn_(&self.args_require_lvalue())
+    pub fn macro_rules_with_captures(&self, captured: &BTreeSet<String>) -> ast::MacroRules {
+        assert!(self.invs_internally_structurally_compatible());
+        self.invocations[0].macro_rules_with_captures(captured)
+    }
+
+    pub fn macro_rules_with_crate_qualified_paths(
+        &self,
+        crate_qualified: &HashMap<String, String>,
+    ) -> ast::MacroRules {
+        assert!(self.invs_internally_structurally_compatible());
+        self.invocations[0].macro_rules_with_crate_qualified_paths(crate_qualified)
+    }
+
+    pub fn macro_rules_with_hygiene(
+        &self,
+        captured: &BTreeSet<String>,
+        crate_qualified: &HashMap<String, String>,
+    ) -> ast::MacroRules {
+        assert!(self.invs_internally_structurally_compatible());
+        self.invocations[0].macro_rules_with_hygiene(captured, crate_qualified)
+    }
+
+    pub fn fn_(&self, sema: Option<&hir::Semantics<'_, ide::RootDatabase>>) -> ast::Fn {
+        assert!(self.invs_internally_type_compatible(sema));
+        self.invocations[0].fn_(&self.args_require_lvalue(), sema)
     }
 
     pub fn args_require_lvalue(&self) -> Vec<bool> {
@@ -1066,21 +1834,432 @@ impl HayrollMacroDB {
     pub fn from_hayroll_macro_invs(hayroll_macros: &Vec<HayrollMacroInv>) -> Self {
         let mut db = HayrollMacroDB::new();
         for mac in hayroll_macros.iter() {
-            let loc_decl = mac.loc_ref_begin();
-            let signature = mac.signature();
-            let key = (loc_decl, signature);
-            if !db.map.contains_key(&key) {
-                db.map.insert(
-                    key.clone(),
-                    HayrollMacroCluster {
-                        invocations: Vec::new(),
-                    },
-                );
-            }
-            db.map.get_mut(&key).unwrap().invocations.push(mac.clone());
+            db.insert_inv(mac);
         }
         db
     }
+
+    // Buckets a single invocation into its (locRefBegin, signature) cluster, creating the cluster
+    // if this is its first member. Factored out of `from_hayroll_macro_invs` so incremental
+    // re-extraction (`splice_invs_from_file` below) can insert freshly parsed invocations into an
+    // existing map the same way a from-scratch build does, instead of duplicating this bucketing.
+    pub fn insert_inv(&mut self, mac: &HayrollMacroInv) {
+        let key = (mac.loc_ref_begin(), mac.signature());
+        self.map
+            .entry(key)
+            .or_insert_with(|| HayrollMacroCluster {
+                invocations: Vec::new(),
+            })
+            .invocations
+            .push(mac.clone());
+    }
+
+    // Drops every invocation sourced from `file_id`, discarding any cluster left with none (a
+    // macro declared in one file but invoked from several keeps its still-valid invocations from
+    // untouched files; only the edited file's subset is removed). Call before re-inserting that
+    // file's freshly reparsed invocations, so a single-file edit doesn't require rebuilding the
+    // whole database the way `from_hayroll_macro_invs` does.
+    pub fn remove_invs_from_file(&mut self, file_id: FileId) {
+        for cluster in self.map.values_mut() {
+            cluster.invocations.retain(|inv| inv.file_id() != file_id);
+        }
+        self.map.retain(|_, cluster| !cluster.invocations.is_empty());
+    }
+
+    // Incremental counterpart to `from_hayroll_macro_invs`: removes `file_id`'s stale invocations
+    // and re-inserts `fresh_invs` (typically just that file's freshly re-extracted invocations),
+    // leaving every cluster the edit didn't touch exactly as it was. Used by the extraction actor
+    // below to splice a single file's re-parse into a long-lived database instead of discarding it.
+    pub fn splice_invs_from_file(&mut self, file_id: FileId, fresh_invs: &[HayrollMacroInv]) {
+        self.remove_invs_from_file(file_id);
+        for mac in fresh_invs.iter() {
+            self.insert_inv(mac);
+        }
+    }
+
+    // `map` already buckets invocations by (locRefBegin, signature), so invocations with a
+    // differently-shaped call site land in separate clusters and would otherwise reconstruct as
+    // N separately-named macros for what was one C macro. Group those clusters back together by
+    // locRefBegin (the declaration site) and synthesize one macro with one arm per structurally
+    // distinct cluster, deduplicating arms that happen to produce identical patterns/bodies.
+    // Clusters `can_be_fn(sema)` are left out: those are reconstructed as a plain `fn` (or unified
+    // into one generic `fn` by `unify_fn_by_loc_decl`) by the caller instead, so a declaration site
+    // never ends up with both a `fn` and a `macro_rules!` for the same invocations. A declaration
+    // site left with only one cluster after that filtering is also absent from the result, since
+    // the caller's ordinary per-cluster `macro_rules()` path already names it correctly on its own.
+    pub fn merge_macro_rules_by_loc_decl(
+        &self,
+        sema: Option<&hir::Semantics<'_, ide::RootDatabase>>,
+    ) -> HashMap<String, ast::MacroRules> {
+        let mut clusters_by_loc_decl: HashMap<&String, Vec<&HayrollMacroCluster>> = HashMap::new();
+        for ((loc_ref_begin, _signature), cluster) in self.map.iter() {
+            if cluster.can_be_fn(sema) {
+                continue;
+            }
+            clusters_by_loc_decl.entry(loc_ref_begin).or_default().push(cluster);
+        }
+        clusters_by_loc_decl
+            .into_iter()
+            .filter(|(_, clusters)| clusters.len() > 1)
+            .map(|(loc_ref_begin, mut clusters)| {
+                clusters.sort_by_key(|cluster| cluster.invocations[0].loc_begin());
+                (loc_ref_begin.clone(), merge_clusters_into_macro_rules(&clusters))
+            })
+            .collect()
+    }
+
+    // The `fn_` analogue of `merge_macro_rules_by_loc_decl` above: groups clusters sharing a
+    // declaration site the same way, then attempts `unify_fn_clusters` on each group instead of
+    // unconditionally merging into `macro_rules!` arms. A declaration site with only one signature,
+    // or whose signatures can't be unified into a single generic `fn`, is simply absent from the
+    // returned map -- callers keep using the ordinary per-cluster `can_be_fn`/`fn_` decision for
+    // those, exactly as before this method existed.
+    pub fn unify_fn_by_loc_decl(
+        &self,
+        sema: Option<&hir::Semantics<'_, ide::RootDatabase>>,
+    ) -> HashMap<String, ast::Fn> {
+        let mut clusters_by_loc_decl: HashMap<&String, Vec<&HayrollMacroCluster>> = HashMap::new();
+        for ((loc_ref_begin, _signature), cluster) in self.map.iter() {
+            clusters_by_loc_decl.entry(loc_ref_begin).or_default().push(cluster);
+        }
+        clusters_by_loc_decl
+            .into_iter()
+            .filter_map(|(loc_ref_begin, clusters)| {
+                unify_fn_clusters(&clusters, sema).map(|fn_| (loc_ref_begin.clone(), fn_))
+            })
+            .collect()
+    }
+}
+
+// A long-lived worker that owns a `HayrollMacroDB` and incrementally re-extracts it as individual
+// files change (via `HayrollMacroDB::splice_invs_from_file`), instead of a caller re-running
+// `from_hayroll_macro_invs` over the whole workspace on every edit; `watch_core::run` drives this
+// by mtime-polling. A single worker thread behind a request channel debounces rapid successive
+// `Restart`s onto the latest one for each file; `Cancel` can't interrupt a parse already in flight, so it just
+// marks the request superseded and lets the progress channel report `Cancelled` instead of
+// `Completed` for it.
+pub enum ExtractionRequest {
+    Restart(FileId, SourceFile),
+    Cancel(FileId),
+    Shutdown,
+}
+
+pub enum ExtractionProgress {
+    Started(FileId),
+    Completed(FileId),
+    Cancelled(FileId),
+}
+
+// Successive requests for the same worker that arrive within this window of each other are drained
+// as one batch, keeping only the latest request for each file, so a burst of edits costs one
+// re-extraction per distinct file rather than one pass per edit.
+const EXTRACTION_DEBOUNCE: std::time::Duration = std::time::Duration::from_millis(150);
+
+pub struct ExtractionActorHandle {
+    requests: std::sync::mpsc::Sender<ExtractionRequest>,
+    pub progress: std::sync::mpsc::Receiver<ExtractionProgress>,
+    pub db: std::sync::Arc<std::sync::Mutex<HayrollMacroDB>>,
+    worker: Option<std::thread::JoinHandle<()>>,
+}
+
+impl ExtractionActorHandle {
+    pub fn spawn() -> Self {
+        let (requests_tx, requests_rx) = std::sync::mpsc::channel();
+        let (progress_tx, progress_rx) = std::sync::mpsc::channel();
+        let db = std::sync::Arc::new(std::sync::Mutex::new(HayrollMacroDB::new()));
+        let worker_db = std::sync::Arc::clone(&db);
+        let worker = std::thread::spawn(move || {
+            extraction_worker_loop(requests_rx, progress_tx, worker_db)
+        });
+        ExtractionActorHandle {
+            requests: requests_tx,
+            progress: progress_rx,
+            db,
+            worker: Some(worker),
+        }
+    }
+
+    // Re-extracts `root` (the edited file's current syntax tree), splicing the result back into
+    // the shared database once done. Supersedes a request for the same file still pending in the
+    // same debounce batch; an extraction already running is not interrupted.
+    pub fn restart(&self, file_id: FileId, root: SourceFile) {
+        let _ = self.requests.send(ExtractionRequest::Restart(file_id, root));
+    }
+
+    // Supersedes a `Restart` for `file_id` still pending in the same debounce batch, without
+    // scheduling a new one, and reports `Cancelled`; an extraction already running completes.
+    pub fn cancel(&self, file_id: FileId) {
+        let _ = self.requests.send(ExtractionRequest::Cancel(file_id));
+    }
+}
+
+impl Drop for ExtractionActorHandle {
+    fn drop(&mut self) {
+        let _ = self.requests.send(ExtractionRequest::Shutdown);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+// The `FileId` a request is debounced by -- `watch_core::run` enqueues one `Restart`/`Cancel` per
+// changed file, independently of any other file, so coalescing must key on this instead of
+// collapsing the whole batch onto whichever request happened to arrive last.
+fn request_file_id(request: &ExtractionRequest) -> Option<FileId> {
+    match request {
+        ExtractionRequest::Restart(file_id, _) => Some(*file_id),
+        ExtractionRequest::Cancel(file_id) => Some(*file_id),
+        ExtractionRequest::Shutdown => None,
+    }
+}
+
+fn extraction_worker_loop(
+    requests: std::sync::mpsc::Receiver<ExtractionRequest>,
+    progress: std::sync::mpsc::Sender<ExtractionProgress>,
+    db: std::sync::Arc<std::sync::Mutex<HayrollMacroDB>>,
+) {
+    loop {
+        let Ok(first) = requests.recv() else {
+            break;
+        };
+        if matches!(first, ExtractionRequest::Shutdown) {
+            break;
+        }
+        // Drain whatever else has queued up within the debounce window, keeping only the most
+        // recent request *per file* (`watch_core::run` already bumped each changed file's mtime
+        // before enqueueing its `Restart`, so a request dropped here would never be retried), so
+        // a burst of rapid edits collapses onto one pass per distinct file instead of one pass
+        // total.
+        let mut pending: HashMap<FileId, ExtractionRequest> = HashMap::new();
+        if let Some(file_id) = request_file_id(&first) {
+            pending.insert(file_id, first);
+        }
+        let mut shutdown = false;
+        loop {
+            match requests.recv_timeout(EXTRACTION_DEBOUNCE) {
+                Ok(ExtractionRequest::Shutdown) => {
+                    shutdown = true;
+                    break;
+                }
+                Ok(next) => {
+                    if let Some(file_id) = request_file_id(&next) {
+                        pending.insert(file_id, next);
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        for (_, request) in pending {
+            match request {
+                ExtractionRequest::Shutdown => unreachable!("Shutdown is never keyed by file_id"),
+                ExtractionRequest::Cancel(file_id) => {
+                    let _ = progress.send(ExtractionProgress::Cancelled(file_id));
+                }
+                ExtractionRequest::Restart(file_id, root) => {
+                    let _ = progress.send(ExtractionProgress::Started(file_id));
+                    let mut roots = HashMap::new();
+                    roots.insert(file_id, root);
+                    let (seeds, _seed_diagnostics) = extract_hayroll_seeds_from_syntax_roots(&roots);
+                    let (invs, _inv_diagnostics) = extract_hayroll_macro_invs_from_seeds(&seeds);
+                    db.lock().unwrap().splice_invs_from_file(file_id, &invs);
+                    let _ = progress.send(ExtractionProgress::Completed(file_id));
+                }
+            }
+        }
+        if shutdown {
+            break;
+        }
+    }
+}
+
+// One arm per structurally distinct cluster sharing a declaration site, deduplicated by
+// (pattern, body) so two clusters that happen to produce identical arm text don't both appear.
+fn merge_clusters_into_macro_rules(clusters: &[&HayrollMacroCluster]) -> ast::MacroRules {
+    assert!(!clusters.is_empty());
+    let macro_name = clusters[0].name();
+
+    let mut seen_arms: std::collections::HashSet<(String, String)> = std::collections::HashSet::new();
+    let arms: Vec<String> = clusters
+        .iter()
+        .filter_map(|cluster| {
+            assert!(cluster.invs_internally_structurally_compatible());
+            let (pattern, body) = cluster.invocations[0].macro_rules_arm();
+            seen_arms
+                .insert((pattern.clone(), body.clone()))
+                .then(|| format!("    ({}) => {{\n    {}\n    }}", pattern, body))
+        })
+        .collect();
+
+    let macro_def = format!(
+        "macro_rules! {}\n{{\n{}\n}}",
+        macro_name,
+        arms.join(";\n")
+    );
+    prettify_generated_node(ast_from_text::<ast::MacroRules>(&macro_def)).clone_for_update()
+}
+
+// A single argument or return position's generalized type while unifying several
+// same-declaration-site clusters' signatures into one generic `fn` (see `unify_fn_clusters`):
+// either every cluster agreed on the same concrete type there, or they disagreed on an otherwise
+// unifiable leaf type and a fresh `T<n>` parameter now stands in for all of them.
+enum GenType {
+    Concrete(ast::Type),
+    Var(String),
+}
+
+impl std::fmt::Display for GenType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GenType::Concrete(ty) => write!(f, "{}", ty.syntax().text()),
+            GenType::Var(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+// Whether every type at one position already agrees (`Same`), or they disagree but are all bare
+// path types (`i32`, `c_long`, ...) and so can stand in for a single generalized type variable
+// (`Generalizable`). `None` means this position can't be unified at all: a pointer, reference,
+// tuple, array or other composite type constructor differing between clusters would need its own
+// recursive unification to bridge faithfully, which this first-order pass deliberately doesn't
+// attempt -- the caller falls back to today's one-`fn`-per-signature behavior in that case.
+enum PositionShape {
+    Same(ast::Type),
+    Generalizable,
+}
+
+fn classify_position(types: &[ast::Type]) -> Option<PositionShape> {
+    let first_text = types[0].syntax().text().to_string();
+    if types
+        .iter()
+        .all(|ty| ty.syntax().text().to_string() == first_text)
+    {
+        return Some(PositionShape::Same(types[0].clone()));
+    }
+    if types.iter().all(|ty| matches!(ty, ast::Type::PathType(_))) {
+        Some(PositionShape::Generalizable)
+    } else {
+        None
+    }
+}
+
+// Recovers every cluster in `clusters` (one declaration site, split by `signature()` because
+// their concrete argument/return types differ) as a single generic `fn`, instead of one `fn` per
+// signature. Requires every cluster to qualify as a plain function (`can_be_fn`) and all of them
+// to be structurally compatible, with only concrete leaf types varying position-by-position (see
+// `classify_position`); each such position becomes a `Copy`-bounded generic parameter. Returns
+// `None` -- caller falls back to one `fn` per signature -- if any of that doesn't hold.
+pub fn unify_fn_clusters(
+    clusters: &[&HayrollMacroCluster],
+    sema: Option<&hir::Semantics<'_, ide::RootDatabase>>,
+) -> Option<ast::Fn> {
+    assert!(!clusters.is_empty());
+    if clusters.len() == 1 || !clusters.iter().all(|c| c.can_be_fn(sema)) {
+        return None;
+    }
+    let first = clusters[0];
+    if !clusters
+        .iter()
+        .all(|c| c.invocations[0].is_structurally_compatible_with(&first.invocations[0]))
+    {
+        return None;
+    }
+
+    let arg_count = first.invocations[0].args.len();
+    let args_require_lvalue: Vec<bool> = (0..arg_count)
+        .map(|i| clusters.iter().all(|c| c.args_require_lvalue()[i]))
+        .collect();
+
+    let mut next_var = 0usize;
+    let mut fresh_var = || {
+        let name = format!("T{next_var}");
+        next_var += 1;
+        name
+    };
+
+    // Return type (absent entirely for a statement-context expansion, in which case every cluster
+    // must agree there's no return type at all).
+    let return_types: Vec<Option<ast::Type>> = clusters
+        .iter()
+        .map(|c| {
+            c.invocations[0]
+                .seed
+                .ptr_or_base_type()
+                .or_else(|| sema.and_then(|sema| c.invocations[0].seed.semantic_type(sema)))
+        })
+        .collect();
+    let return_gen = if return_types.iter().all(Option::is_none) {
+        None
+    } else {
+        let types: Vec<ast::Type> = return_types.into_iter().collect::<Option<Vec<_>>>()?;
+        match classify_position(&types)? {
+            PositionShape::Same(ty) => Some(GenType::Concrete(ty)),
+            PositionShape::Generalizable => Some(GenType::Var(fresh_var())),
+        }
+    };
+
+    let mut arg_gens: Vec<(String, GenType)> = Vec::with_capacity(arg_count);
+    for i in 0..arg_count {
+        let arg_name = first.invocations[0].args[i].0.clone();
+        let requires_lvalue = args_require_lvalue[i];
+        let mut types = Vec::with_capacity(clusters.len());
+        for c in clusters.iter() {
+            let (_, arg_regions) = &c.invocations[0].args[i];
+            let seed = arg_regions.first()?;
+            let ty = if requires_lvalue {
+                seed.ptr_or_base_type()
+                    .or_else(|| sema.and_then(|sema| seed.semantic_type(sema)))
+            } else {
+                seed.base_type()
+                    .or_else(|| sema.and_then(|sema| seed.semantic_type(sema)))
+            };
+            types.push(ty?);
+        }
+        let gen = match classify_position(&types)? {
+            PositionShape::Same(ty) => GenType::Concrete(ty),
+            PositionShape::Generalizable => GenType::Var(fresh_var()),
+        };
+        arg_gens.push((arg_name, gen));
+    }
+
+    // Only worth it if at least one position actually needed a variable; if every cluster already
+    // agreed on every type, `signature()` would have put them in the same cluster to begin with.
+    if next_var == 0 {
+        return None;
+    }
+
+    let type_params = (0..next_var).map(|i| format!("T{i}")).collect::<Vec<_>>().join(", ");
+    let where_clause = (0..next_var)
+        .map(|i| format!("T{i}: Copy"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let return_type_text = match &return_gen {
+        Some(gen) => format!(" -> {}", gen),
+        None => String::new(),
+    };
+    let arg_with_types = arg_gens
+        .iter()
+        .map(|(name, gen)| format!("{}: {}", name, gen))
+        .collect::<Vec<String>>()
+        .join(", ");
+    let fn_body =
+        first
+            .invocations[0]
+            .replace_arg_regions_into(true, false, &args_require_lvalue, |arg_region| {
+                let name = arg_region.name();
+                let name_token = ast::make::tokens::ident(&name);
+                let name_node = name_token.parent().unwrap().clone_for_update();
+                vec![syntax::NodeOrToken::Node(name_node)]
+            });
+    let fn_text = format!(
+        "unsafe fn {}<{}>({}){} where {}\n{{\n    {}\n}}",
+        first.name(),
+        type_params,
+        arg_with_types,
+        return_type_text,
+        where_clause,
+        fn_body
+    );
+    Some(prettify_generated_node(ast_from_text::<ast::Fn>(&fn_text)).clone_for_update())
 }
 
 #[derive(Clone)]
@@ -1097,7 +2276,26 @@ impl HasHayrollTag for HayrollConditionalMacro {
 impl HayrollConditionalMacro {
     // Attach #[cfg(premise)] to every element in the code region
     // Returns a list of ted-style delayed tasks to be executed later
-    pub fn attach_cfg_teds(&self, builder: &mut SourceChangeBuilderSet) -> Vec<Box<dyn FnOnce()>> {
+    // `sema`, when available, lets region-climbing fall through into macro expansions for seeds
+    // that now live inside a macro call's token tree (see `HayrollSeed::get_raw_code_region_macro_aware`).
+    // `compile_time_select`, when set, changes the `HayrollSeed::Expr` arm below from the runtime
+    // `if cfg!(premise) { .. } else { .. }` (both branches always compiled, so the inactive one
+    // must still type-check under every configuration) to attribute-selected branches, so only one
+    // is ever fed to the compiler -- see `compile_time_select_expr_text`. The `Stmts`/`Decls` arms
+    // already attach `#[cfg(premise)]` directly to items/statements, which is compile-time
+    // selection already; this flag has no effect on them.
+    // `premise_dict`, when given, resolves any alias tokens in the premise through
+    // `PremiseDictionary::resolve` before it's spliced into the generated `#[cfg(...)]` for the
+    // `Stmts`/`Decls` arms -- not the `Expr` arm, which already renders its premise through
+    // `Premise`'s own combinator logic via `compile_time_select_expr_text` and isn't the textual
+    // `#[cfg(...)]`-attribute path this dictionary targets.
+    pub fn attach_cfg_teds(
+        &self,
+        builder: &mut SourceChangeBuilderSet,
+        sema: Option<&hir::Semantics<'_, ide::RootDatabase>>,
+        compile_time_select: bool,
+        premise_dict: Option<&PremiseDictionary>,
+    ) -> Vec<Box<dyn FnOnce()>> {
         let mut teds: Vec<Box<dyn FnOnce()>> = Vec::new();
         // Force attaching cfg to a placeholder decl/decls is a hack
         // We do this because Maki frequently thinks a decl/decls is a placeholder
@@ -1109,7 +2307,7 @@ impl HayrollConditionalMacro {
         match self.seed {
             HayrollSeed::Expr(_) => {
                 // Work on the underlying IfExpr (don't include outer deref for lvalues)
-                let region = self.seed.get_raw_code_region(false);
+                let region = self.seed.get_raw_code_region_macro_aware(false, sema);
                 if let CodeRegion::Expr(expr) = &region {
                     // Expect the current expr to be an if-expr produced by instrumentation
                     let if_expr = ast::IfExpr::cast(expr.syntax().clone())
@@ -1121,15 +2319,19 @@ impl HayrollConditionalMacro {
                         ast::ElseBranch::Block(b) => b.to_string(),
                         ast::ElseBranch::IfExpr(e) => e.to_string(),
                     };
-                    let new_expr_text = format!(
-                        "{{ if cfg!({}) {} else {} }}",
-                        // No extra braces around then and else branches because they are already blocks
-                        // But provide extra braces around the whole if-expression to help replacement
-                        premise,
-                        then_text,
-                        else_text
-                    );
-                    let new_expr_mut = expr_from_text(&new_expr_text).clone_for_update();
+                    let new_expr_text = if compile_time_select {
+                        compile_time_select_expr_text(&premise, &then_text, &else_text)
+                    } else {
+                        format!(
+                            "{{ if cfg!({}) {} else {} }}",
+                            // No extra braces around then and else branches because they are already blocks
+                            // But provide extra braces around the whole if-expression to help replacement
+                            premise,
+                            then_text,
+                            else_text
+                        )
+                    };
+                    let new_expr_mut = prettify_generated_node(expr_from_text(&new_expr_text)).clone_for_update();
                     let _if_expr_mut = builder.make_mut(if_expr);
                     let then_branch_mut = builder.make_mut(then_branch);
                     teds.push(Box::new(move || {
@@ -1140,7 +2342,8 @@ impl HayrollConditionalMacro {
                 }
             }
             HayrollSeed::Stmts(_, _) => {
-                let region = self.seed.get_raw_code_region_inside_tag();
+                let premise = premise_dict.map(|dict| dict.resolve(&premise)).unwrap_or(premise);
+                let region = self.seed.get_raw_code_region_inside_tag_macro_aware(sema);
                 // Print this code region for debugging
                 if let CodeRegion::Stmts { parent, range } = &region {
                     for (i, stmt) in parent.statements().enumerate() {
@@ -1227,7 +2430,8 @@ impl HayrollConditionalMacro {
                 }
             }
             HayrollSeed::Decls(_) => {
-                let region = self.seed.get_raw_code_region(false);
+                let premise = premise_dict.map(|dict| dict.resolve(&premise)).unwrap_or(premise);
+                let region = self.seed.get_raw_code_region_macro_aware(false, sema);
                 if region.is_empty() {
                     return Vec::new();
                 }
@@ -1250,128 +2454,1009 @@ impl HayrollConditionalMacro {
     }
 }
 
+// Builds the attribute-selected form of a conditional expression: a `let` gated by
+// `#[cfg(premise)]` and a second, shadowing `let` of the same name gated by `#[cfg(not(premise))]`,
+// with the name as the block's tail -- since only one `#[cfg]` ever survives compilation, this
+// reduces to exactly one of the two blocks, unlike `if cfg!(premise) { .. } else { .. }`, which
+// compiles and type-checks both unconditionally. `premise` is parsed and re-rendered through
+// `Premise::negate`/`to_cfg_string` to produce the `not(...)` form; if parsing fails (an
+// unrecognized premise shape), falls back to wrapping the raw text in `not(...)` textually, same
+// as `Premise::to_cfg_string` would have rendered for an `Atom`.
+fn compile_time_select_expr_text(premise: &str, then_text: &str, else_text: &str) -> String {
+    let not_premise = Premise::parse(premise)
+        .map(|p| p.negate().to_cfg_string())
+        .unwrap_or_else(|_| format!("not({})", premise));
+    format!(
+        "{{ #[cfg({premise})] let __hayroll_cfg_sel = {then_text}; #[cfg({not_premise})] let __hayroll_cfg_sel = {else_text}; __hayroll_cfg_sel }}",
+        premise = premise,
+        then_text = then_text,
+        not_premise = not_premise,
+        else_text = else_text,
+    )
+}
+
+// Boolean AST for a Hayroll conditional seed's `premise` string, which is the same cfg-predicate
+// shape `HayrollConditionalMacro` already splices verbatim into `cfg!(...)` / `#[cfg(...)]`
+// (a bare atom -- one of the macro's `arg_names()` -- or `all(...)` / `any(...)` / `not(...)`
+// built from atoms and nested combinators), now actually parsed instead of treated as opaque text.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Premise {
+    Atom(String),
+    Not(Box<Premise>),
+    All(Vec<Premise>),
+    Any(Vec<Premise>),
+}
+
+impl Premise {
+    pub fn parse(s: &str) -> Result<Premise, String> {
+        let tokens = tokenize_premise(s)?;
+        let mut pos = 0;
+        let premise = parse_premise_tokens(&tokens, &mut pos)?;
+        if pos != tokens.len() {
+            return Err(format!("trailing tokens after premise: {:?}", &tokens[pos..]));
+        }
+        Ok(premise)
+    }
+
+    // Renders back into the same cfg-predicate text `HayrollConditionalMacro` would have spliced
+    // in directly, so a merged premise can be embedded the same way an unparsed one is.
+    pub fn to_cfg_string(&self) -> String {
+        match self {
+            Premise::Atom(name) => name.clone(),
+            Premise::Not(inner) => format!("not({})", inner.to_cfg_string()),
+            Premise::All(parts) => {
+                format!("all({})", parts.iter().map(Premise::to_cfg_string).collect::<Vec<_>>().join(", "))
+            }
+            Premise::Any(parts) => {
+                format!("any({})", parts.iter().map(Premise::to_cfg_string).collect::<Vec<_>>().join(", "))
+            }
+        }
+    }
+
+    // Negates a premise, collapsing a double negation (`not(not(p))` -> `p`) instead of piling up
+    // a redundant `not(not(...))` wrapper, since this is applied to already-parsed premises that
+    // may themselves be a `Not`.
+    pub fn negate(self) -> Premise {
+        match self {
+            Premise::Not(inner) => *inner,
+            other => Premise::Not(Box::new(other)),
+        }
+    }
+
+    fn collect_atoms(&self, out: &mut BTreeSet<String>) {
+        match self {
+            Premise::Atom(name) => {
+                out.insert(name.clone());
+            }
+            Premise::Not(inner) => inner.collect_atoms(out),
+            Premise::All(parts) | Premise::Any(parts) => {
+                parts.iter().for_each(|p| p.collect_atoms(out))
+            }
+        }
+    }
+
+    fn eval(&self, truth: &HashMap<String, bool>) -> bool {
+        match self {
+            Premise::Atom(name) => *truth.get(name).unwrap_or(&false),
+            Premise::Not(inner) => !inner.eval(truth),
+            Premise::All(parts) => parts.iter().all(|p| p.eval(truth)),
+            Premise::Any(parts) => parts.iter().any(|p| p.eval(truth)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PremiseToken {
+    Ident(String),
+    LParen,
+    RParen,
+    Comma,
+}
+
+fn tokenize_premise(s: &str) -> Result<Vec<PremiseToken>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '(' {
+            chars.next();
+            tokens.push(PremiseToken::LParen);
+        } else if c == ')' {
+            chars.next();
+            tokens.push(PremiseToken::RParen);
+        } else if c == ',' {
+            chars.next();
+            tokens.push(PremiseToken::Comma);
+        } else if c.is_alphanumeric() || c == '_' {
+            let mut ident = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_alphanumeric() || c == '_' {
+                    ident.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            tokens.push(PremiseToken::Ident(ident));
+        } else {
+            return Err(format!("unexpected character {:?} in premise {:?}", c, s));
+        }
+    }
+    Ok(tokens)
+}
+
+// `not(p)` and `all(p, q, ...)` / `any(p, q, ...)` are the only combinators the cfg-predicate
+// grammar has, so there's no precedence to climb -- a single lookahead on the next ident is enough
+// to tell a combinator call from a bare atom.
+fn parse_premise_tokens(tokens: &[PremiseToken], pos: &mut usize) -> Result<Premise, String> {
+    let Some(token) = tokens.get(*pos) else {
+        return Err("expected a premise, found end of input".to_string());
+    };
+    let PremiseToken::Ident(name) = token else {
+        return Err(format!("expected an identifier, found {:?}", token));
+    };
+    *pos += 1;
+    if tokens.get(*pos) != Some(&PremiseToken::LParen) {
+        return Ok(Premise::Atom(name.clone()));
+    }
+    *pos += 1; // consume '('
+    let mut args = vec![parse_premise_tokens(tokens, pos)?];
+    while tokens.get(*pos) == Some(&PremiseToken::Comma) {
+        *pos += 1;
+        args.push(parse_premise_tokens(tokens, pos)?);
+    }
+    if tokens.get(*pos) != Some(&PremiseToken::RParen) {
+        return Err(format!("expected ')' after {}(...) arguments", name));
+    }
+    *pos += 1; // consume ')'
+    match name.as_str() {
+        "not" if args.len() == 1 => Ok(Premise::Not(Box::new(args.pop().unwrap()))),
+        "not" => Err(format!("not(...) takes exactly one argument, got {}", args.len())),
+        "all" => Ok(Premise::All(args)),
+        "any" => Ok(Premise::Any(args)),
+        other => Err(format!("unknown premise combinator {:?}", other)),
+    }
+}
+
+// A layered premise-alias dictionary, modeled on Mercurial's config-file layering: a `.premises`
+// file maps a short alias (e.g. `linux_or_bsd`) to the full cfg fragment it stands for
+// (`any(target_os = "linux", target_os = "freebsd")`), `%include <path>` pulls in another file's
+// aliases at the line it appears on (resolved relative to the including file, so shared dictionaries can live
+// anywhere), and `%unset <alias>` drops an alias inherited from an earlier include rather than
+// merely shadowing it. `load_files` loads several files in sequence, each one's entries
+// overriding a same-named alias already loaded from an earlier file -- the layering `%include`
+// can't express on its own, since it only reaches within one file's own directive list.
+#[derive(Clone, Debug, Default)]
+pub struct PremiseDictionary {
+    aliases: HashMap<String, String>,
+}
+
+impl PremiseDictionary {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load_file(path: &Path) -> Result<PremiseDictionary, String> {
+        let mut dict = PremiseDictionary::new();
+        dict.merge_file(path)?;
+        Ok(dict)
+    }
+
+    pub fn load_files(paths: &[PathBuf]) -> Result<PremiseDictionary, String> {
+        let mut dict = PremiseDictionary::new();
+        for path in paths {
+            dict.merge_file(path)?;
+        }
+        Ok(dict)
+    }
+
+    // Applies one file's `%include`/`%unset` directives and `alias = cfg fragment` entries on top
+    // of whatever this dictionary already holds, in line order. An `%include` is merged at the line
+    // it appears on, so entries after it in this file override its aliases and entries before it
+    // are overridden by them.
+    fn merge_file(&mut self, path: &Path) -> Result<(), String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read premise dictionary {}: {}", path.display(), e))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        for (lineno, raw_line) in text.lines().enumerate() {
+            let line = raw_line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(included) = line.strip_prefix("%include") {
+                self.merge_file(&dir.join(included.trim()))?;
+            } else if let Some(name) = line.strip_prefix("%unset") {
+                self.aliases.remove(name.trim());
+            } else if let Some((name, fragment)) = line.split_once('=') {
+                self.aliases.insert(name.trim().to_string(), fragment.trim().to_string());
+            } else {
+                return Err(format!(
+                    "{}:{}: expected `alias = cfg fragment`, `%include <path>`, or `%unset <alias>`, found {:?}",
+                    path.display(),
+                    lineno + 1,
+                    raw_line
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Resolves any alias tokens in `premise` through this dictionary. Tries the structured route
+    // first -- parse `premise` as a `Premise`, substitute any `Atom` whose name is a dictionary key
+    // with its expansion, and re-render -- since that also reaches an alias nested inside
+    // `all(...)`/`any(...)`/`not(...)`. Falls back to returning `premise` unchanged when it doesn't
+    // parse as a `Premise` (e.g. it's already a raw cfg fragment rather than a bare alias/combinator
+    // of aliases), same conservative fallback `compile_time_select_expr_text` uses for an
+    // unparseable premise.
+    pub fn resolve(&self, premise: &str) -> String {
+        if self.aliases.is_empty() {
+            return premise.to_string();
+        }
+        match Premise::parse(premise) {
+            Ok(parsed) => self.resolve_premise(&parsed).to_cfg_string(),
+            Err(_) => premise.to_string(),
+        }
+    }
+
+    fn resolve_premise(&self, premise: &Premise) -> Premise {
+        match premise {
+            Premise::Atom(name) => match self.aliases.get(name) {
+                Some(fragment) => Premise::Atom(fragment.clone()),
+                None => Premise::Atom(name.clone()),
+            },
+            Premise::Not(inner) => Premise::Not(Box::new(self.resolve_premise(inner))),
+            Premise::All(parts) => Premise::All(parts.iter().map(|p| self.resolve_premise(p)).collect()),
+            Premise::Any(parts) => Premise::Any(parts.iter().map(|p| self.resolve_premise(p)).collect()),
+        }
+    }
+}
+
+// Brute-force truth table over the premises' combined atoms: cheap since a conditional macro's
+// premises are built from a handful of its own `arg_names()`, not arbitrary predicates. Returns
+// (mutually_exclusive, exhaustive): exclusive means no assignment makes more than one premise
+// true; exhaustive means every assignment makes at least one premise true.
+fn premises_mutually_exclusive_and_exhaustive(premises: &[Premise]) -> (bool, bool) {
+    let mut atoms = BTreeSet::new();
+    premises.iter().for_each(|p| p.collect_atoms(&mut atoms));
+    let atoms: Vec<String> = atoms.into_iter().collect();
+    let assignment_count = 1u32.checked_shl(atoms.len() as u32).unwrap_or(u32::MAX);
+    let mut mutually_exclusive = true;
+    let mut exhaustive = true;
+    for bits in 0..assignment_count {
+        let truth: HashMap<String, bool> = atoms
+            .iter()
+            .enumerate()
+            .map(|(i, atom)| (atom.clone(), (bits >> i) & 1 == 1))
+            .collect();
+        let true_count = premises.iter().filter(|p| p.eval(&truth)).count();
+        if true_count > 1 {
+            mutually_exclusive = false;
+        }
+        if true_count == 0 {
+            exhaustive = false;
+        }
+    }
+    (mutually_exclusive, exhaustive)
+}
+
+// Decodes a Hayroll tag literal's byte-string payload back into its trimmed JSON text, the same
+// way `extract_hayroll_seeds_from_syntax_roots_impl` does when first discovering tags. Used by
+// `HayrollConditionalMerge` to re-parse the literal `with_appended_merged_variants` just produced,
+// so successive variants can be folded onto it one at a time.
+fn decode_hayroll_tag_literal(literal: &ast::Literal) -> Option<String> {
+    let token = literal.syntax().first_token()?;
+    let byte_str = ast::ByteString::cast(token)?;
+    let content = String::from_utf8_lossy(&byte_str.value().ok()?).to_string();
+    Some(content.trim_end_matches(char::from(0)).to_string())
+}
+
+// Merges several structurally-compatible conditional `HayrollSeed`s that each cover the same
+// original region under a different `premise` into one region a downstream rewrite pass can
+// splice in place of all N separate conditional expansions. Mirrors `HayrollConditionalMacro`'s
+// per-variant `#[cfg(...)]`/`cfg!(...)` gating, just folded into a single synthesized region
+// instead of N independent ones.
+pub struct HayrollConditionalMerge {
+    pub seeds: Vec<HayrollSeed>,
+}
+
+impl HayrollConditionalMerge {
+    pub fn new(seeds: Vec<HayrollSeed>) -> Result<HayrollConditionalMerge, String> {
+        let Some(first) = seeds.first() else {
+            return Err("cannot merge an empty group of conditional seeds".to_string());
+        };
+        if !seeds.iter().all(|seed| seed.is_conditional()) {
+            return Err("HayrollConditionalMerge requires every seed to be conditional".to_string());
+        }
+        if !seeds.iter().all(|seed| seed.is_structurally_compatible_with(first)) {
+            return Err("conditional seeds to merge must be structurally compatible".to_string());
+        }
+        Ok(HayrollConditionalMerge { seeds })
+    }
+
+    fn premises(&self) -> Result<Vec<Premise>, String> {
+        self.seeds.iter().map(|seed| Premise::parse(&seed.premise())).collect()
+    }
+
+    // Warns via `tracing::warn` (doesn't fail the merge) when the premises aren't mutually
+    // exclusive and exhaustive: the synthesized if/else-if chain picks the first true branch and
+    // the synthesized cfg-gated decls simply stack independent attrs, so overlapping or
+    // incomplete premises here silently produce a result that picks a different variant (or none)
+    // than the original per-premise expansions would have, which is worth flagging even though
+    // it's not fatal to producing *a* merged region.
+    pub fn check_premises(&self) {
+        let premises = match self.premises() {
+            Ok(premises) => premises,
+            Err(message) => {
+                warn!(error = %message, "Could not parse premises for conditional merge");
+                return;
+            }
+        };
+        let (mutually_exclusive, exhaustive) = premises_mutually_exclusive_and_exhaustive(&premises);
+        let premise_strings: Vec<String> = self.seeds.iter().map(|seed| seed.premise()).collect();
+        if !mutually_exclusive {
+            warn!(premises = ?premise_strings, "Conditional merge premises are not mutually exclusive");
+        }
+        if !exhaustive {
+            warn!(premises = ?premise_strings, "Conditional merge premises are not exhaustive");
+        }
+    }
+
+    // Folds every seed after the first onto the first seed's tag via `with_appended_merged_variants`,
+    // re-parsing the literal it returns each time so the next fold sees the accumulated list.
+    // Returns the final merged tag literal, recording every contributing variant's name, for a
+    // downstream pass to splice in alongside `merge`'s region (not wired into `merge` itself,
+    // since `CodeRegion` has no slot of its own for a tag literal).
+    #[allow(dead_code)]
+    pub fn merged_tag_literal(&self) -> ast::Literal {
+        let first = &self.seeds[0];
+        let mut cur: HayrollTag = first.hayroll_tag().clone();
+        for seed in &self.seeds[1..] {
+            let new_literal = cur.with_appended_merged_variants(&seed.name());
+            let content = decode_hayroll_tag_literal(&new_literal)
+                .expect("with_appended_merged_variants must produce a decodable byte-string literal");
+            cur = HayrollTag::parse(&content, new_literal, cur.file_id)
+                .expect("with_appended_merged_variants must produce a re-parseable HayrollTagData");
+        }
+        cur.literal
+    }
+
+    // Synthesizes the merged region. `sema`, when available, lets region-climbing fall through
+    // into macro expansions the same way `HayrollConditionalMacro::attach_cfg_teds` does.
+    pub fn merge(&self, sema: Option<&hir::Semantics<'_, ide::RootDatabase>>) -> CodeRegion {
+        self.check_premises();
+        let regions: Vec<CodeRegion> = self
+            .seeds
+            .iter()
+            .map(|seed| seed.get_raw_code_region_macro_aware(false, sema))
+            .collect();
+        match &regions[0] {
+            CodeRegion::Expr(_) => self.merge_expr_regions(&regions),
+            CodeRegion::Stmts { .. } => self.merge_stmts_regions(&regions),
+            CodeRegion::Decls(_) => self.merge_decls_regions(&regions),
+        }
+    }
+
+    fn merge_expr_regions(&self, regions: &[CodeRegion]) -> CodeRegion {
+        // Walk variants in reverse so each arm's "else" is the chain already built from the
+        // variants after it, ending in `unreachable!()` -- a diverging expression fits whatever
+        // type the branches share, which is the safest fallback when premises turn out not to be
+        // exhaustive (flagged separately by `check_premises`).
+        let mut chain_text = "unreachable!(\"no premise matched in conditional merge\")".to_string();
+        for (seed, region) in self.seeds.iter().zip(regions.iter()).rev() {
+            let CodeRegion::Expr(expr) = region else {
+                panic!("Expected Expr region for conditional merge");
+            };
+            let if_expr = ast::IfExpr::cast(expr.syntax().clone())
+                .expect("Expected IfExpr for conditional seed expr");
+            let then_text = if_expr.then_branch().expect("IfExpr must have then branch").to_string();
+            chain_text = format!(
+                "if cfg!({}) {} else {{ {} }}",
+                seed.premise(),
+                then_text,
+                chain_text
+            );
+        }
+        let merged_expr = prettify_generated_node(expr_from_text(&chain_text)).clone_for_update();
+        CodeRegion::Expr(merged_expr)
+    }
+
+    fn merge_stmts_regions(&self, regions: &[CodeRegion]) -> CodeRegion {
+        let mut chain_text = String::new();
+        for (seed, region) in self.seeds.iter().zip(regions.iter()) {
+            let CodeRegion::Stmts { parent, range } = region else {
+                panic!("Expected Stmts region for conditional merge");
+            };
+            let stmts_text: String = parent
+                .statements()
+                .enumerate()
+                .filter(|(i, _)| range.contains(i))
+                .map(|(_, stmt)| stmt.to_string())
+                .collect::<Vec<_>>()
+                .join("\n");
+            let keyword = if chain_text.is_empty() { "if" } else { "else if" };
+            chain_text.push_str(&format!("{} cfg!({}) {{\n{}\n}} ", keyword, seed.premise(), stmts_text));
+        }
+        // `ast_from_text` parses its argument as a whole `SourceFile`, so a bare block isn't valid
+        // top-level syntax -- go through `expr_from_text` (which wraps it in `const C: () = { .. };`
+        // the same way the rest of this file builds standalone expressions) and pull the
+        // resulting `BlockExpr`'s `StmtList` back out.
+        let block_text = format!("{{ {} }}", chain_text);
+        let block_expr = prettify_generated_node(expr_from_text(&block_text)).clone_for_update();
+        let merged_block = ast::BlockExpr::cast(block_expr.syntax().clone())
+            .and_then(|block| block.stmt_list())
+            .expect("merged conditional stmts must parse as a block");
+        CodeRegion::Stmts {
+            parent: merged_block,
+            range: 0..=0,
+        }
+    }
+
+    fn merge_decls_regions(&self, regions: &[CodeRegion]) -> CodeRegion {
+        let mut merged_items: Vec<ast::Item> = Vec::new();
+        for (seed, region) in self.seeds.iter().zip(regions.iter()) {
+            let CodeRegion::Decls(items) = region else {
+                panic!("Expected Decls region for conditional merge");
+            };
+            let attr_text = format!("#[cfg({})]", seed.premise());
+            for item in items {
+                let item_mut = item.clone_for_update();
+                let attr = ast_from_text::<ast::Attr>(&attr_text).clone_for_update();
+                item_mut.add_attr(attr);
+                merged_items.push(item_mut);
+            }
+        }
+        CodeRegion::Decls(merged_items)
+    }
+}
+
+// A bare identifier expression: a single unqualified, non-generic path segment (e.g. `x`, not
+// `x::y` or `x::<T>`), the shape `macro_rules!` needs for an `:ident` fragment.
+fn is_bare_ident_expr(expr: &ast::Expr) -> bool {
+    let Some(path_expr) = ast::PathExpr::cast(expr.syntax().clone()) else {
+        return false;
+    };
+    let Some(path) = path_expr.path() else {
+        return false;
+    };
+    path.qualifier().is_none()
+        && path
+            .segment()
+            .map_or(false, |seg| seg.generic_arg_list().is_none() && seg.name_ref().is_some())
+}
+
+// Whether `text` also parses as a standalone type (e.g. a generic type like `Vec<i32>`, which
+// fails every one of the other `fragment_specifier_for_expr` checks but still needs a `:ty`
+// fragment to compile once substituted back into a type position). `expr`'s own node can never
+// cast to `ast::Type` -- it's already committed to the disjoint `Expr` family of `SyntaxKind`s --
+// so this reparses the argument's raw token text from scratch, wrapped in a type-alias item the
+// same way `ast_from_text::<ast::Type>` callers synthesize types elsewhere in this file.
+fn parses_as_type(text: &str) -> bool {
+    let parse = SourceFile::parse(&format!("type T = {text};"), syntax::Edition::CURRENT);
+    parse.errors().is_empty()
+        && parse
+            .tree()
+            .syntax()
+            .descendants()
+            .find_map(ast::Type::cast)
+            .is_some_and(|ty| ty.syntax().text().to_string() == text)
+}
+
+fn fragment_specifier_for_expr(expr: &ast::Expr) -> &'static str {
+    let syntax = expr.syntax();
+
+    if is_bare_ident_expr(expr) {
+        "ident"
+    } else if ast::Literal::can_cast(syntax.kind()) {
+        "literal"
+    } else if ast::PathExpr::can_cast(syntax.kind()) {
+        "path"
+    } else if parses_as_type(&syntax.text().to_string()) {
+        "ty"
+    } else if ast::BlockExpr::can_cast(syntax.kind()) {
+        "block"
+    } else {
+        "expr"
+    }
+}
+
+// A non-fatal problem found while pairing or matching Hayroll tags (an unmatched begin/end tag,
+// an unrecognized tag shape, or an argument region with no enclosing macro invocation). Carries
+// enough source location to let a caller with access to the `Vfs` report or navigate to the
+// offending tag. Collecting these instead of panicking lets the rest of a run's seeds and
+// invocations be extracted even when one region is malformed.
+#[derive(Clone, Debug)]
+pub struct HayrollDiagnostic {
+    pub message: String,
+    pub file_id: FileId,
+    pub range: syntax::TextRange,
+    // The partner tag's location and a short label for it, for diagnostics about a begin/end
+    // mismatch where a second tag is genuinely implicated (e.g. an end tag whose begin closed
+    // under a different seed type). `None` for diagnostics with no natural second location, like
+    // an unmatched tag or a literal that fails to parse at all.
+    pub secondary: Option<(FileId, syntax::TextRange, String)>,
+}
+
+impl HayrollDiagnostic {
+    fn from_tag(message: impl Into<String>, tag: &HayrollTag) -> HayrollDiagnostic {
+        HayrollDiagnostic {
+            message: message.into(),
+            file_id: tag.file_id,
+            range: tag.literal.syntax().text_range(),
+            secondary: None,
+        }
+    }
+
+    // Like `from_tag`, but also anchors a secondary annotation on `partner_tag` (e.g. the begin
+    // tag an ill-matched end tag closed against), labeled with `secondary_label`.
+    fn from_tag_with_secondary(
+        message: impl Into<String>,
+        tag: &HayrollTag,
+        partner_tag: &HayrollTag,
+        secondary_label: impl Into<String>,
+    ) -> HayrollDiagnostic {
+        HayrollDiagnostic {
+            secondary: Some((
+                partner_tag.file_id,
+                partner_tag.literal.syntax().text_range(),
+                secondary_label.into(),
+            )),
+            ..HayrollDiagnostic::from_tag(message, tag)
+        }
+    }
+
+    // Like `from_tag`, but for a byte-string literal that identifies itself as a Hayroll tag
+    // (carries `"hayroll":true`) yet fails to deserialize into `HayrollTagData` -- there's no
+    // successfully-constructed `HayrollTag` to anchor the diagnostic on, since construction
+    // itself is what failed, so this takes the literal's own location directly instead.
+    fn from_literal(message: impl Into<String>, file_id: FileId, literal: &ast::Literal) -> HayrollDiagnostic {
+        HayrollDiagnostic {
+            message: message.into(),
+            file_id,
+            range: literal.syntax().text_range(),
+            secondary: None,
+        }
+    }
+
+    pub fn to_json(&self, vfs: &vfs::Vfs) -> serde_json::Value {
+        serde_json::json!({
+            "message": self.message,
+            "file": vfs.file_path(self.file_id).to_string(),
+            "start": u32::from(self.range.start()),
+            "end": u32::from(self.range.end()),
+        })
+    }
+
+    // Serializes in the shape `rustc --error-format=json` uses, so a GitHub Actions problem
+    // matcher or an editor's LSP-style consumer can parse it directly: `message`/`code`/`level`
+    // at top level, and a `spans` array with byte offsets alongside rustc's own 1-based
+    // `line_start`/`line_end`/`column_start`/`column_end`. The primary span comes first
+    // (`is_primary: true`); `secondary`, when set, becomes a second, non-primary span with its own
+    // `label` rather than rustc's nested `children` (this diagnostic has no sub-diagnostics of its
+    // own, just a second location worth highlighting).
+    pub fn to_rustc_json(
+        &self,
+        vfs: &vfs::Vfs,
+        syntax_roots: &HashMap<FileId, SourceFile>,
+        level: &str,
+    ) -> serde_json::Value {
+        let mut spans = vec![diagnostic_span_json(
+            vfs,
+            syntax_roots,
+            self.file_id,
+            self.range,
+            true,
+            None,
+        )];
+        if let Some((secondary_file, secondary_range, secondary_label)) = &self.secondary {
+            spans.push(diagnostic_span_json(
+                vfs,
+                syntax_roots,
+                *secondary_file,
+                *secondary_range,
+                false,
+                Some(secondary_label.as_str()),
+            ));
+        }
+        serde_json::json!({
+            "message": self.message,
+            "code": serde_json::Value::Null,
+            "level": level,
+            "spans": spans,
+        })
+    }
+
+    // Renders this diagnostic as a rich, source-spanned `annotate-snippets` report: the offending
+    // byte-string literal's surrounding source line(s) with a primary annotation on its range, and
+    // -- when `secondary` is set -- a second annotation on the partner tag, even when it's in a
+    // different file (rendered as its own titled snippet rather than forced into one `Snippet`,
+    // since `annotate-snippets` expects every annotation within a `Snippet` to share one `source`).
+    // Byte offsets are converted to line/column via a `LineIndex` built fresh per call; callers
+    // rendering many diagnostics against the same files may want to cache these themselves.
+    pub fn render(&self, vfs: &vfs::Vfs, syntax_roots: &HashMap<FileId, SourceFile>) -> String {
+        use annotate_snippets::{Level, Renderer, Snippet};
+
+        let Some(primary_root) = syntax_roots.get(&self.file_id) else {
+            return self.message.clone();
+        };
+        let primary_source = primary_root.syntax().text().to_string();
+        let primary_index = ide::LineIndex::new(&primary_source);
+        let primary_path = vfs.file_path(self.file_id).to_string();
+        let primary_start = primary_index.line_col(self.range.start());
+        let primary_line_range = source_snippet_line_range(&primary_source, self.range);
+
+        let message = Level::Error.title(&self.message).snippet(
+            Snippet::source(&primary_source[primary_line_range.clone()])
+                .line_start(primary_start.line as usize + 1)
+                .origin(&primary_path)
+                .fold(true)
+                .annotation(
+                    Level::Error
+                        .span(
+                            (u32::from(self.range.start()) as usize - primary_line_range.start)
+                                ..(u32::from(self.range.end()) as usize - primary_line_range.start),
+                        )
+                        .label(&self.message),
+                ),
+        );
+        let mut report = Renderer::styled().render(message).to_string();
+
+        if let Some((secondary_file, secondary_range, secondary_label)) = &self.secondary {
+            if let Some(secondary_root) = syntax_roots.get(secondary_file) {
+                let secondary_source = secondary_root.syntax().text().to_string();
+                let secondary_index = ide::LineIndex::new(&secondary_source);
+                let secondary_path = vfs.file_path(*secondary_file).to_string();
+                let secondary_start = secondary_index.line_col(secondary_range.start());
+                let secondary_line_range =
+                    source_snippet_line_range(&secondary_source, *secondary_range);
+                let secondary_message = Level::Note.title(secondary_label).snippet(
+                    Snippet::source(&secondary_source[secondary_line_range.clone()])
+                        .line_start(secondary_start.line as usize + 1)
+                        .origin(&secondary_path)
+                        .fold(true)
+                        .annotation(
+                            Level::Note
+                                .span(
+                                    (u32::from(secondary_range.start()) as usize
+                                        - secondary_line_range.start)
+                                        ..(u32::from(secondary_range.end()) as usize
+                                            - secondary_line_range.start),
+                                )
+                                .label(secondary_label),
+                        ),
+                );
+                report.push('\n');
+                report.push_str(&Renderer::styled().render(secondary_message).to_string());
+            }
+        }
+        report
+    }
+}
+
+// Widens a byte range to cover its enclosing source line(s), the way `annotate-snippets` expects
+// its `Snippet::source` to be handed a whole-line slice rather than an arbitrary mid-line span.
+fn source_snippet_line_range(source: &str, range: syntax::TextRange) -> std::ops::Range<usize> {
+    let start = u32::from(range.start()) as usize;
+    let end = u32::from(range.end()) as usize;
+    let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[end..].find('\n').map_or(source.len(), |i| end + i);
+    line_start..line_end
+}
+
+// Logs each diagnostic (with its source location resolved through `vfs`) plus a final summary
+// count, so malformed regions stay visible without aborting the run that found them.
+pub fn log_hayroll_diagnostics(vfs: &vfs::Vfs, diagnostics: &[HayrollDiagnostic]) {
+    for diag in diagnostics {
+        warn!(file = %vfs.file_path(diag.file_id), range = ?diag.range, "{}", diag.message);
+    }
+    if !diagnostics.is_empty() {
+        warn!(
+            count = diagnostics.len(),
+            "Hayroll tag pairing/matching produced diagnostics"
+        );
+    }
+}
+
+// One span entry of a `to_rustc_json` diagnostic. Line/column are resolved through a `LineIndex`
+// built fresh per call, same as `HayrollDiagnostic::render`; rustc's own span format is 1-based on
+// both line and column, so `LineIndex`'s 0-based `LineCol` is offset by one in each direction.
+fn diagnostic_span_json(
+    vfs: &vfs::Vfs,
+    syntax_roots: &HashMap<FileId, SourceFile>,
+    file_id: FileId,
+    range: syntax::TextRange,
+    is_primary: bool,
+    label: Option<&str>,
+) -> serde_json::Value {
+    let (line_start, column_start, line_end, column_end) = match syntax_roots.get(&file_id) {
+        Some(root) => {
+            let source = root.syntax().text().to_string();
+            let index = ide::LineIndex::new(&source);
+            let start = index.line_col(range.start());
+            let end = index.line_col(range.end());
+            (start.line + 1, start.col + 1, end.line + 1, end.col + 1)
+        }
+        // The file isn't in the syntax roots this caller happened to pass in (e.g. a diagnostic
+        // rendered after the workspace was reloaded); still emit a span so the JSON stream stays
+        // one-object-per-diagnostic, just without resolvable line/column.
+        None => (0, 0, 0, 0),
+    };
+    serde_json::json!({
+        "file_name": vfs.file_path(file_id).to_string(),
+        "byte_start": u32::from(range.start()),
+        "byte_end": u32::from(range.end()),
+        "line_start": line_start,
+        "line_end": line_end,
+        "column_start": column_start,
+        "column_end": column_end,
+        "is_primary": is_primary,
+        "label": label,
+    })
+}
+
+// Selects how `emit_hayroll_diagnostics` below reports a batch of diagnostics: `Human` keeps the
+// existing `tracing::warn`-based summary (`log_hayroll_diagnostics`); `Json` prints one
+// `to_rustc_json` object per line on stdout, the way `rustc --error-format=json` does, so the
+// output can be consumed by a GitHub Actions problem matcher or an editor's LSP-style client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiagnosticOutputFormat {
+    Human,
+    Json,
+}
+
+pub fn emit_hayroll_diagnostics(
+    format: DiagnosticOutputFormat,
+    vfs: &vfs::Vfs,
+    syntax_roots: &HashMap<FileId, SourceFile>,
+    diagnostics: &[HayrollDiagnostic],
+) {
+    match format {
+        DiagnosticOutputFormat::Human => log_hayroll_diagnostics(vfs, diagnostics),
+        DiagnosticOutputFormat::Json => {
+            for diag in diagnostics {
+                println!(
+                    "{}",
+                    diag.to_rustc_json(vfs, syntax_roots, "warning")
+                );
+            }
+        }
+    }
+}
+
+// Selects how extraction reacts to a malformed tag or arg region. `Resilient` (the default used
+// by every `_with_mode`-less entry point below) records the problem as a `HayrollDiagnostic` and
+// keeps going, dropping only the one irrecoverable seed/arg -- rust-analyzer's own
+// resilient-parsing philosophy, ported here so a large, partially-instrumented codebase can be
+// processed end to end and a user sees every issue in one pass instead of fixing them one at a
+// time. `Strict` instead panics on the first one, for interactive/debugging use where seeing the
+// very first issue immediately (with a full backtrace) matters more than surfacing every issue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExtractMode {
+    Strict,
+    Resilient,
+}
+
+// Records `diag` under `Resilient`, or panics on it immediately under `Strict`.
+fn report_or_panic(mode: ExtractMode, diagnostics: &mut Vec<HayrollDiagnostic>, diag: HayrollDiagnostic) {
+    match mode {
+        ExtractMode::Resilient => diagnostics.push(diag),
+        ExtractMode::Strict => panic!("{}", diag.message),
+    }
+}
+
 pub fn extract_hayroll_macro_invs_from_seeds(
     hayroll_seeds: &Vec<HayrollSeed>,
-) -> Vec<HayrollMacroInv> {
-    // A region whose isArg is false is a macro; match args to their macro
-    let hayroll_macro_invs: Vec<HayrollMacroInv> = hayroll_seeds
-        .iter()
-        .filter(|seed| seed.is_invocation())
-        .fold(Vec::new(), |mut acc, region| {
-            if region.is_arg() == false {
-                // Pre-populate all expected argument names with empty vectors
-                let preset_args: Vec<(String, Vec<HayrollSeed>)> = region
-                    .arg_names()
-                    .into_iter()
-                    .map(|name| (name, Vec::new()))
-                    .collect();
-                acc.push(HayrollMacroInv {
-                    seed: region.clone(),
-                    args: preset_args,
-                });
-            } else {
-                let mut found = false;
-                for mac in acc.iter_mut().rev() {
-                    if mac.loc_begin() == region.loc_ref_begin() {
-                        assert!(mac.args.iter().any(|(name, _)| name == &region.name()));
-                        let arg = mac
-                            .args
-                            .iter_mut()
-                            .find(|(name, _)| name == &region.name())
-                            .unwrap();
-                        arg.1.push(region.clone());
-                        found = true;
-                        break;
+) -> (Vec<HayrollMacroInv>, Vec<HayrollDiagnostic>) {
+    extract_hayroll_macro_invs_from_seeds_with_mode(hayroll_seeds, ExtractMode::Resilient)
+}
+
+pub fn extract_hayroll_macro_invs_from_seeds_with_mode(
+    hayroll_seeds: &Vec<HayrollSeed>,
+    mode: ExtractMode,
+) -> (Vec<HayrollMacroInv>, Vec<HayrollDiagnostic>) {
+    // A region whose isArg is false is a macro; match args to their macro. `invs_by_loc_inv`
+    // indexes each registered invocation's position in `hayroll_macro_invs` by its own locBegin, so
+    // an argument region is matched by a single map lookup on its locRefBegin instead of a linear
+    // rev-scan over every invocation seen so far. Seeds are visited in `hayroll_seeds` order, so an
+    // argument only matches an invocation registered before it; an argument whose invocation comes
+    // later in that order is reported as unmatched.
+    let mut hayroll_macro_invs: Vec<HayrollMacroInv> = Vec::new();
+    let mut diagnostics: Vec<HayrollDiagnostic> = Vec::new();
+    let mut invs_by_loc_inv: HashMap<String, usize> = HashMap::new();
+    for region in hayroll_seeds.iter().filter(|seed| seed.is_invocation()) {
+        if region.is_arg() == false {
+            // Pre-populate all expected argument names with empty vectors
+            let preset_args: Vec<(String, Vec<HayrollSeed>)> = region
+                .arg_names()
+                .into_iter()
+                .map(|name| (name, Vec::new()))
+                .collect();
+            let idx = hayroll_macro_invs.len();
+            hayroll_macro_invs.push(HayrollMacroInv {
+                seed: region.clone(),
+                args: preset_args,
+            });
+            invs_by_loc_inv.insert(region.loc_begin(), idx);
+        } else {
+            match invs_by_loc_inv.get(&region.loc_ref_begin()) {
+                Some(&idx) => {
+                    let mac = &mut hayroll_macro_invs[idx];
+                    match mac.args.iter_mut().find(|(name, _)| name == &region.name()) {
+                        Some(arg) => arg.1.push(region.clone()),
+                        None => report_or_panic(
+                            mode,
+                            &mut diagnostics,
+                            HayrollDiagnostic::from_tag(
+                                format!(
+                                    "argument '{}' is not declared among the macro's expected arguments",
+                                    region.name()
+                                ),
+                                region.first_tag(),
+                            ),
+                        ),
                     }
                 }
-                if !found {
-                    panic!("No matching macro found for arg: {:?}", region.loc_begin());
-                }
+                None => report_or_panic(
+                    mode,
+                    &mut diagnostics,
+                    HayrollDiagnostic::from_tag(
+                        format!(
+                            "no matching macro invocation found for argument at {}",
+                            region.loc_begin()
+                        ),
+                        region.first_tag(),
+                    ),
+                ),
             }
-            acc
-        });
-    hayroll_macro_invs
+        }
+    }
+    (hayroll_macro_invs, diagnostics)
 }
 
-// Returns a list of HayrollSeed and unmatched HayrollTag
+// Returns the extracted HayrollSeeds, any unmatched begin HayrollTags, and a list of
+// non-fatal diagnostics recorded while pairing/matching tags.
 pub fn extract_hayroll_seeds_from_syntax_roots_impl(
     syntax_roots: &HashMap<FileId, SourceFile>,
-) -> (Vec<HayrollSeed>, Vec<HayrollTag>) {
-    let hayroll_tags: Vec<HayrollTag> = syntax_roots
-        .iter()
-        .flat_map(|(file_id, root)| {
-            root.syntax()
-                .descendants_with_tokens()
-                // Attach a file_id to each node
-                .map(move |element| (element, file_id))
-        })
-        .filter_map(|(element, file_id)| {
-            if let Some(token) = element.clone().into_token() {
-                if let Some(byte_str) = ast::ByteString::cast(token) {
-                    // Try to parse into serde_json::Value, if it fails, it's not a JSON string
-                    let content = match byte_str.value() {
-                        Ok(cow) => String::from_utf8_lossy(&cow).to_string(),
-                        Err(_) => return None,
-                    };
-                    // Delete the last \0 byte
-                    let content = content.trim_end_matches(char::from(0));
-                    let tag_res = serde_json::from_str::<serde_json::Value>(&content);
-                    trace!(byte_string = %content, tag = ?tag_res, "Byte String parsed");
-                    if let Ok(tag) = tag_res {
-                        if tag["hayroll"] == true {
-                            let tag = HayrollTag {
-                                literal: ast::Literal::cast(element.parent()?)?,
-                                tag,
-                                file_id: file_id.clone(),
-                            };
-                            return Some(tag);
-                        }
-                    }
-                }
+) -> (Vec<HayrollSeed>, Vec<HayrollTag>, Vec<HayrollDiagnostic>) {
+    extract_hayroll_seeds_from_syntax_roots_impl_with_mode(syntax_roots, ExtractMode::Resilient)
+}
+
+pub fn extract_hayroll_seeds_from_syntax_roots_impl_with_mode(
+    syntax_roots: &HashMap<FileId, SourceFile>,
+    mode: ExtractMode,
+) -> (Vec<HayrollSeed>, Vec<HayrollTag>, Vec<HayrollDiagnostic>) {
+    // Two-stage parse: a lenient `serde_json::Value` probe decides whether a byte string is even
+    // trying to be a Hayroll tag (checking only the `hayroll` flag), and only then is the strict,
+    // fallible `HayrollTagData` parse attempted. This keeps unrelated JSON-shaped byte strings
+    // elsewhere in the file (which would never deserialize into `HayrollTagData`, missing most of
+    // its required fields) silently ignored as before, while a byte string that DOES claim
+    // `"hayroll":true` but fails the strict parse is now a diagnostic instead of a panic the first
+    // time some `HayrollMeta` accessor indexes a field it's missing.
+    let mut hayroll_tags: Vec<HayrollTag> = Vec::new();
+    let mut diagnostics: Vec<HayrollDiagnostic> = Vec::new();
+    for (file_id, root) in syntax_roots.iter() {
+        for element in root.syntax().descendants_with_tokens() {
+            let Some(token) = element.clone().into_token() else {
+                continue;
+            };
+            let Some(byte_str) = ast::ByteString::cast(token) else {
+                continue;
+            };
+            let content = match byte_str.value() {
+                Ok(cow) => String::from_utf8_lossy(&cow).to_string(),
+                Err(_) => continue,
+            };
+            // Delete the last \0 byte
+            let content = content.trim_end_matches(char::from(0));
+            let probe_res = serde_json::from_str::<serde_json::Value>(content);
+            trace!(byte_string = %content, tag = ?probe_res, "Byte String parsed");
+            let Ok(probe) = probe_res else {
+                continue;
+            };
+            if probe["hayroll"] != true {
+                continue;
             }
-            None
-        })
-        .collect();
+            let Some(literal) = element.parent().and_then(ast::Literal::cast) else {
+                continue;
+            };
+            match HayrollTag::parse(content, literal.clone(), file_id.clone()) {
+                Ok(tag) => hayroll_tags.push(tag),
+                Err(message) => report_or_panic(
+                    mode,
+                    &mut diagnostics,
+                    HayrollDiagnostic::from_literal(message, file_id.clone(), &literal),
+                ),
+            }
+        }
+    }
 
-    // Pair up stmt hayroll_literals that are in the same scope and share the locInv in info
-    let hayroll_seeds: Vec<HayrollSeed> = hayroll_tags.iter().fold(Vec::new(), |mut acc, tag| {
+    // Pair up stmt hayroll_literals that are in the same scope and share the locInv in info.
+    // `open_stmts_by_loc_inv` tracks, per (locInv, seedType), the stack of still-open
+    // `HayrollSeed::Stmts` indices in `hayroll_seeds` (innermost pushed last); a `begin==false`
+    // tag pops its own `(locInv, seedType)` key's top of stack rather than linearly rescanning
+    // every seed collected so far, so a macro invocation's stmt region can nest inside another's
+    // (or be interleaved with unrelated regions in the same scope) and still close in the right
+    // order. Keying by the pair rather than locInv alone also means two regions that happen to
+    // share a locInv but differ in seedType (e.g. a conditional region whose locInv collides with
+    // an unrelated invocation's) sit on separate stacks instead of one popping the other's still-
+    // open frame and reporting a spurious seed-type mismatch.
+    let mut hayroll_seeds: Vec<HayrollSeed> = Vec::new();
+    let mut open_stmts_by_loc_inv: HashMap<(String, SeedType), Vec<usize>> = HashMap::new();
+    for tag in hayroll_tags.iter() {
         if tag.is_expr() {
             assert!(tag.begin());
-            acc.push(HayrollSeed::Expr(tag.clone()));
+            hayroll_seeds.push(HayrollSeed::Expr(tag.clone()));
         } else if (tag.is_stmt() || tag.is_stmts()) && tag.begin() == true {
-            acc.push(HayrollSeed::Stmts(tag.clone(), tag.clone())); // For now seedBegin == seedEnd
+            let idx = hayroll_seeds.len();
+            hayroll_seeds.push(HayrollSeed::Stmts(tag.clone(), tag.clone())); // For now seedBegin == seedEnd
+            open_stmts_by_loc_inv
+                .entry((tag.loc_begin(), tag.seed_type()))
+                .or_default()
+                .push(idx);
         } else if tag.is_decl() || tag.is_decls() {
             assert!(tag.begin());
-            acc.push(HayrollSeed::Decls(tag.clone()));
+            hayroll_seeds.push(HayrollSeed::Decls(tag.clone()));
         } else if !tag.begin() {
-            // Search through the acc to find the begin stmt with the same locInv
-            let mut found = false;
-            for seed in acc.iter_mut().rev() {
-                match seed {
-                    HayrollSeed::Stmts(tag_begin, ref mut tag_end) => {
-                        if tag_begin.loc_begin() == tag.loc_begin()
-                            && tag_begin.seed_type() == tag.seed_type()
-                            && tag.begin() == false
-                        {
+            match open_stmts_by_loc_inv
+                .get_mut(&(tag.loc_begin(), tag.seed_type()))
+                .and_then(|stack| stack.pop())
+            {
+                Some(idx) => match &mut hayroll_seeds[idx] {
+                    HayrollSeed::Stmts(tag_begin, tag_end) => {
+                        if tag_begin.seed_type() == tag.seed_type() {
                             *tag_end = tag.clone();
-                            found = true;
-                            break;
+                        } else {
+                            report_or_panic(
+                                mode,
+                                &mut diagnostics,
+                                HayrollDiagnostic::from_tag_with_secondary(
+                                    format!(
+                                        "end stmt {} does not match its begin stmt's seed type",
+                                        tag.loc_begin()
+                                    ),
+                                    tag,
+                                    tag_begin,
+                                    "begin tag with the conflicting seed type",
+                                ),
+                            );
                         }
                     }
-                    _ => {}
-                }
-            }
-            if !found {
-                panic!(
-                    "No matching begin stmt found for end stmt {}",
-                    tag.loc_begin()
-                );
+                    _ => report_or_panic(
+                        mode,
+                        &mut diagnostics,
+                        HayrollDiagnostic::from_tag(
+                            format!(
+                                "end stmt {} does not match its begin stmt's seed type",
+                                tag.loc_begin()
+                            ),
+                            tag,
+                        ),
+                    ),
+                },
+                // Unmatched end tag: the begin it would have closed was dropped or never emitted.
+                // There is no seed to leave in place for this one (unlike an unmatched *begin*,
+                // there's no partial `Stmts(begin, begin)` to keep), so resilient mode's recovery
+                // here is simply to drop the end tag and move on.
+                None => report_or_panic(
+                    mode,
+                    &mut diagnostics,
+                    HayrollDiagnostic::from_tag(
+                        format!("no matching begin stmt found for end stmt {}", tag.loc_begin()),
+                        tag,
+                    ),
+                ),
             }
         } else {
-            panic!("Unknown tag");
+            report_or_panic(
+                mode,
+                &mut diagnostics,
+                HayrollDiagnostic::from_tag(
+                    "unrecognized Hayroll tag shape (neither expr, stmt, decl, nor end marker)",
+                    tag,
+                ),
+            );
         }
-        acc
-    });
+    }
 
     // Collect unmatched begin stmt tags
     let unmatched_begin_tags: Vec<HayrollTag> = hayroll_seeds
@@ -1390,26 +3475,363 @@ pub fn extract_hayroll_seeds_from_syntax_roots_impl(
             }
         })
         .collect();
+    for tag in &unmatched_begin_tags {
+        // The corresponding `Stmts(begin, begin)` seed above is left in `hayroll_seeds` as-is
+        // (see the filter above) rather than dropped, so resilient callers still get a usable,
+        // if incomplete, seed alongside the report.
+        report_or_panic(
+            mode,
+            &mut diagnostics,
+            HayrollDiagnostic::from_tag(
+                format!("unmatched Hayroll begin tag {}", tag.loc_begin()),
+                tag,
+            ),
+        );
+    }
 
-    (hayroll_seeds, unmatched_begin_tags)
+    (hayroll_seeds, unmatched_begin_tags, diagnostics)
 }
 
+// Returns the extracted seeds together with any diagnostics recorded while pairing tags (see
+// `HayrollDiagnostic`). Malformed regions are skipped rather than aborting the whole extraction;
+// callers with access to the workspace `Vfs` are expected to log the diagnostics and decide
+// whether the run should still proceed.
 pub fn extract_hayroll_seeds_from_syntax_roots(
     syntax_roots: &HashMap<FileId, SourceFile>,
-) -> Vec<HayrollSeed> {
-    let (seeds, unmatched) = extract_hayroll_seeds_from_syntax_roots_impl(syntax_roots);
-    if !unmatched.is_empty() {
-        for tag in unmatched {
-            error!("Unmatched begin tag: {}", tag.loc_begin());
-        }
-        panic!("Unmatched begin tags found");
+) -> (Vec<HayrollSeed>, Vec<HayrollDiagnostic>) {
+    extract_hayroll_seeds_from_syntax_roots_with_mode(syntax_roots, ExtractMode::Resilient)
+}
+
+pub fn extract_hayroll_seeds_from_syntax_roots_with_mode(
+    syntax_roots: &HashMap<FileId, SourceFile>,
+    mode: ExtractMode,
+) -> (Vec<HayrollSeed>, Vec<HayrollDiagnostic>) {
+    let (seeds, _unmatched, diagnostics) =
+        extract_hayroll_seeds_from_syntax_roots_impl_with_mode(syntax_roots, mode);
+    (seeds, diagnostics)
+}
+
+// Strict counterpart to `extract_hayroll_seeds_from_syntax_roots`, for callers that want to treat
+// any pairing/matching diagnostic as fatal instead of logging and continuing -- e.g. a CI-style
+// check that should fail outright on a malformed tag rather than silently skip the region.
+pub fn extract_hayroll_seeds_from_syntax_roots_checked(
+    syntax_roots: &HashMap<FileId, SourceFile>,
+) -> Result<Vec<HayrollSeed>, Vec<HayrollDiagnostic>> {
+    let (seeds, diagnostics) = extract_hayroll_seeds_from_syntax_roots(syntax_roots);
+    if diagnostics.is_empty() {
+        Ok(seeds)
+    } else {
+        Err(diagnostics)
     }
-    seeds
 }
 
+// Distinct from `extract_hayroll_seeds_from_syntax_roots`: this is used by the Zero Pass to find
+// begin tags whose end tag was dropped by C2Rust (e.g. stmt ranges ending in a `return`), so the
+// caller can repair them structurally rather than just report them.
 pub fn extract_unmatched_hayroll_tags_from_syntax_roots(
     syntax_roots: &HashMap<FileId, SourceFile>,
 ) -> Vec<HayrollTag> {
-    let (_seeds, unmatched) = extract_hayroll_seeds_from_syntax_roots_impl(syntax_roots);
+    let (_seeds, unmatched, _diagnostics) = extract_hayroll_seeds_from_syntax_roots_impl(syntax_roots);
     unmatched
 }
+
+// Every Hayroll tag literal in `syntax_roots`, regardless of file, in no particular order -- the
+// raw input `pair_macro_regions` below sorts and pairs per file. A byte string that doesn't parse
+// as a well-formed tag is silently skipped here (this feeds reconstruction ordering, not
+// diagnostics; a malformed tag is already reported elsewhere by
+// `extract_hayroll_seeds_from_syntax_roots`).
+pub fn collect_hayroll_tags_from_syntax_roots(
+    syntax_roots: &HashMap<FileId, SourceFile>,
+) -> Vec<HayrollTag> {
+    let mut hayroll_tags: Vec<HayrollTag> = Vec::new();
+    for (file_id, root) in syntax_roots.iter() {
+        for element in root.syntax().descendants_with_tokens() {
+            let Some(token) = element.into_token() else {
+                continue;
+            };
+            let Some(byte_str) = ast::ByteString::cast(token) else {
+                continue;
+            };
+            let Ok(cow) = byte_str.value() else {
+                continue;
+            };
+            let content = String::from_utf8_lossy(&cow).to_string();
+            let content = content.trim_end_matches(char::from(0));
+            let Ok(probe) = serde_json::from_str::<serde_json::Value>(content) else {
+                continue;
+            };
+            if probe["hayroll"] != true {
+                continue;
+            }
+            let Some(literal) = byte_str.syntax().parent().and_then(ast::Literal::cast) else {
+                continue;
+            };
+            if let Ok(tag) = HayrollTag::parse(content, literal, *file_id) {
+                hayroll_tags.push(tag);
+            }
+        }
+    }
+    hayroll_tags
+}
+
+// A MacroRegion is a reconstructed bracketed span covering a single (possibly nested) macro
+// invocation, assembled by pairing a tag's begin:true/begin:false byte-string literals.
+// `children` are the regions nested directly inside this one (deeper regions sit in their own
+// `children`), in the order their end tags appear.
+#[derive(Clone, Debug)]
+pub struct MacroRegion {
+    pub name: String,
+    pub ast_kind: String,
+    pub loc_inv: String,
+    pub loc_decl: String,
+    pub file_id: FileId,
+    pub range: syntax::TextRange,
+    pub children: Vec<MacroRegion>,
+}
+
+impl MacroRegion {
+    // Depth-first nesting depth of every region reachable from `roots`, keyed by `loc_inv` (root
+    // regions are depth 0). Lets a reconstruction pass that must process inner invocations before
+    // the outer ones containing them (e.g. `signbit!` inside `__raise_overflowf`) sort its work by
+    // depth instead of relying on the caller's own iteration order being coincidentally correct.
+    pub fn nesting_depths(roots: &[MacroRegion]) -> HashMap<String, usize> {
+        fn walk(region: &MacroRegion, depth: usize, out: &mut HashMap<String, usize>) {
+            let entry = out.entry(region.loc_inv.clone()).or_insert(depth);
+            if depth > *entry {
+                *entry = depth;
+            }
+            for child in &region.children {
+                walk(child, depth + 1, out);
+            }
+        }
+        let mut out = HashMap::new();
+        for root in roots {
+            walk(root, 0, &mut out);
+        }
+        out
+    }
+}
+
+// Pairs begin/end tags sharing a (locInv, locDecl, name, seedType) key into a tree of MacroRegion
+// via a stack, same recovery idiom as `extract_hayroll_seeds_from_syntax_roots_impl`: malformed
+// pairings are collected as `HayrollDiagnostic`s and skipped rather than aborting the whole file.
+pub fn pair_macro_regions(file_id: FileId, tags: &[HayrollTag]) -> (Vec<MacroRegion>, Vec<HayrollDiagnostic>) {
+    struct OpenRegion {
+        tag: HayrollTag,
+        children: Vec<MacroRegion>,
+    }
+
+    let region_key = |tag: &HayrollTag| -> (String, String, String, SeedType) {
+        (tag.tag.loc_inv.clone(), tag.tag.loc_decl.clone(), tag.name(), tag.tag.seed_type)
+    };
+
+    let mut sorted_tags: Vec<&HayrollTag> = tags.iter().collect();
+    sorted_tags.sort_by_key(|tag| tag.literal.syntax().text_range().start());
+
+    let mut stack: Vec<OpenRegion> = Vec::new();
+    let mut roots: Vec<MacroRegion> = Vec::new();
+    let mut diagnostics: Vec<HayrollDiagnostic> = Vec::new();
+
+    for tag in sorted_tags {
+        let key = region_key(tag);
+        if tag.begin() {
+            stack.push(OpenRegion {
+                tag: tag.clone(),
+                children: Vec::new(),
+            });
+            continue;
+        }
+
+        let Some(pos) = stack.iter().rposition(|open| region_key(&open.tag) == key) else {
+            diagnostics.push(HayrollDiagnostic::from_tag(
+                format!("unmatched end tag for Hayroll region at locInv: {}", key.0),
+                tag,
+            ));
+            continue;
+        };
+        if pos != stack.len() - 1 {
+            diagnostics.push(HayrollDiagnostic::from_tag_with_secondary(
+                format!(
+                    "mismatched nesting when pairing Hayroll region at locInv: {} (innermost open region is not its partner)",
+                    key.0
+                ),
+                tag,
+                &stack[stack.len() - 1].tag,
+                "innermost still-open region",
+            ));
+            continue;
+        }
+
+        let open = stack.pop().unwrap();
+        let range = open
+            .tag
+            .literal
+            .syntax()
+            .text_range()
+            .cover(tag.literal.syntax().text_range());
+        let region = MacroRegion {
+            name: open.tag.name(),
+            ast_kind: open.tag.ast_kind(),
+            loc_inv: key.0,
+            loc_decl: key.1,
+            file_id,
+            range,
+            children: open.children,
+        };
+        match stack.last_mut() {
+            Some(parent) => parent.children.push(region),
+            None => roots.push(region),
+        }
+    }
+
+    for open in &stack {
+        diagnostics.push(HayrollDiagnostic::from_tag(
+            format!("unclosed Hayroll begin tag at locInv: {}", region_key(&open.tag).0),
+            &open.tag,
+        ));
+    }
+
+    (roots, diagnostics)
+}
+
+// Covers `macro_rules_arm`'s variadic repetition group: the trailing `rest` argument's bound
+// occurrences are exactly the C call site's actual trailing arguments (zero, one, or many), and
+// the pattern must always bind `$($rest:expr),*` regardless of how many of those there turn out to
+// be. Fixtures are built from real source text rather than `HayrollMacroInv` literals so the test
+// also exercises tag probing/pairing and call-site-to-argument matching, not just the arm-building
+// logic in isolation.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syntax::Edition;
+
+    fn byte_string_literal(json: &str) -> String {
+        format!("b\"{}\\0\"", json.replace('"', "\\\""))
+    }
+
+    // The if/else shape the instrumentation pass leaves around every tagged Expr region.
+    fn tagged_expr(tag_json: &str, body: &str) -> String {
+        format!(
+            "if *({} as *const u8 as *const libc::c_char) as libc::c_int != 0 {{ {body} }} else {{ 0 as *mut i32 }}",
+            byte_string_literal(tag_json)
+        )
+    }
+
+    // One `variadic_call` invocation whose variadic `rest` argument is bound to `arg_count`
+    // trailing call-site arguments (`a`, `b`, `c`, ...).
+    fn build_variadic_invocation(arg_count: usize) -> HayrollMacroInv {
+        let call_args = (0..arg_count)
+            .map(|i| {
+                let name = ((b'a' + i as u8) as char).to_string();
+                let tag = format!(
+                    r#"{{"hayroll":true,"seedType":"invocation","isArg":true,"name":"rest","locBegin":"test.c:{line}:1","locEnd":"test.c:{line}:1","cuLnColBegin":"{line}:1","cuLnColEnd":"{line}:1","locRefBegin":"test.c:1:1","astKind":"Expr","begin":true}}"#,
+                    line = i + 2
+                );
+                tagged_expr(&tag, &name)
+            })
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        let inv_tag = r#"{"hayroll":true,"seedType":"invocation","isArg":false,"name":"variadic_call","argNames":["rest"],"isVariadic":true,"locBegin":"test.c:1:1","locEnd":"test.c:1:1","cuLnColBegin":"1:1","cuLnColEnd":"1:1","locRefBegin":"test.c:1:1","astKind":"Expr","begin":true}"#;
+        let source = format!(
+            "fn f() -> i32 {{ {} }}",
+            tagged_expr(inv_tag, &format!("variadic_call({call_args})"))
+        );
+
+        let file_id = FileId::from_raw(0);
+        let mut syntax_roots = HashMap::new();
+        syntax_roots.insert(file_id, SourceFile::parse(&source, Edition::Edition2021).tree());
+
+        let seeds = extract_hayroll_seeds_from_syntax_roots_checked(&syntax_roots)
+            .expect("fixture source should extract cleanly");
+        let (mut invs, diagnostics) = extract_hayroll_macro_invs_from_seeds(&seeds);
+        assert!(diagnostics.is_empty(), "unexpected diagnostics: {diagnostics:?}");
+        assert_eq!(invs.len(), 1, "expected exactly one invocation in the fixture");
+        invs.remove(0)
+    }
+
+    // Same fixture as `build_variadic_invocation`, but every bound occurrence's tag carries
+    // `"argUsage":"{arg_usage}"`, as the instrumentation pass would for a variadic argument that's
+    // counted or only bound for side effects rather than spelled out value by value.
+    fn build_variadic_invocation_with_usage(arg_count: usize, arg_usage: &str) -> HayrollMacroInv {
+        let call_args = (0..arg_count)
+            .map(|i| {
+                let name = ((b'a' + i as u8) as char).to_string();
+                let tag = format!(
+                    r#"{{"hayroll":true,"seedType":"invocation","isArg":true,"name":"rest","argUsage":"{arg_usage}","locBegin":"test.c:{line}:1","locEnd":"test.c:{line}:1","cuLnColBegin":"{line}:1","cuLnColEnd":"{line}:1","locRefBegin":"test.c:1:1","astKind":"Expr","begin":true}}"#,
+                    line = i + 2
+                );
+                tagged_expr(&tag, &name)
+            })
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        let inv_tag = r#"{"hayroll":true,"seedType":"invocation","isArg":false,"name":"variadic_call","argNames":["rest"],"isVariadic":true,"locBegin":"test.c:1:1","locEnd":"test.c:1:1","cuLnColBegin":"1:1","cuLnColEnd":"1:1","locRefBegin":"test.c:1:1","astKind":"Expr","begin":true}"#;
+        let source = format!(
+            "fn f() -> i32 {{ {} }}",
+            tagged_expr(inv_tag, &format!("variadic_call({call_args})"))
+        );
+
+        let file_id = FileId::from_raw(0);
+        let mut syntax_roots = HashMap::new();
+        syntax_roots.insert(file_id, SourceFile::parse(&source, Edition::Edition2021).tree());
+
+        let seeds = extract_hayroll_seeds_from_syntax_roots_checked(&syntax_roots)
+            .expect("fixture source should extract cleanly");
+        let (mut invs, diagnostics) = extract_hayroll_macro_invs_from_seeds(&seeds);
+        assert!(diagnostics.is_empty(), "unexpected diagnostics: {diagnostics:?}");
+        assert_eq!(invs.len(), 1, "expected exactly one invocation in the fixture");
+        invs.remove(0)
+    }
+
+    #[test]
+    fn macro_rules_arm_variadic_count_usage() {
+        let inv = build_variadic_invocation_with_usage(3, "count");
+        let (_, body) = inv.macro_rules_arm();
+        assert!(body.contains("${count(rest)}"), "body: {body}");
+        assert_eq!(body.matches("$($rest),*").count(), 0, "body: {body}");
+    }
+
+    #[test]
+    fn macro_rules_arm_variadic_ignore_usage() {
+        let inv = build_variadic_invocation_with_usage(3, "ignore");
+        let (_, body) = inv.macro_rules_arm();
+        assert!(body.contains("${ignore(rest)}"), "body: {body}");
+        assert_eq!(body.matches("$($rest),*").count(), 0, "body: {body}");
+    }
+
+    #[test]
+    fn macro_rules_arm_variadic_zero_trailing_args() {
+        let inv = build_variadic_invocation(0);
+        let (pattern, body) = inv.macro_rules_arm();
+        assert_eq!(pattern, "$($rest:expr),*");
+        assert!(body.contains("variadic_call()"), "body: {body}");
+        assert_eq!(body.matches("$($rest),*").count(), 0, "body: {body}");
+    }
+
+    #[test]
+    fn macro_rules_arm_variadic_one_trailing_arg() {
+        let inv = build_variadic_invocation(1);
+        let (pattern, body) = inv.macro_rules_arm();
+        assert!(
+            pattern.starts_with("$($rest:") && pattern.ends_with("),*"),
+            "pattern: {pattern}"
+        );
+        assert_eq!(body.matches("$($rest),*").count(), 1, "body: {body}");
+    }
+
+    #[test]
+    fn macro_rules_arm_variadic_many_trailing_args() {
+        let inv = build_variadic_invocation(3);
+        let (pattern, body) = inv.macro_rules_arm();
+        assert!(
+            pattern.starts_with("$($rest:") && pattern.ends_with("),*"),
+            "pattern: {pattern}"
+        );
+        // N >= 2 bound occurrences collapse to a single repetition group spliced once into the
+        // call, not one group per bound argument (which would replay `a, b, c` three times over).
+        assert_eq!(body.matches("$($rest),*").count(), 1, "body: {body}");
+        assert!(body.contains("variadic_call($($rest),*)"), "body: {body}");
+    }
+}
+
@@ -6,12 +6,17 @@ use tracing::error;
 fn main() -> Result<()> {
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
-        error!(usage = %format!("Usage: {} <workspace-path>", args[0]));
+        error!(usage = %format!("Usage: {} <workspace-path> [--proc-macros] [--validate]", args[0]));
         std::process::exit(1);
     }
 
     util::init_logging();
 
     let workspace_path = Path::new(&args[1]);
-    inliner_core::run(workspace_path)
+    // Opt-in: also expand derive/attribute/function-like proc macros, not just macro_rules!
+    // and builtins, at the cost of starting a proc-macro server for the workspace.
+    let enable_proc_macros = args.iter().skip(2).any(|arg| arg == "--proc-macros");
+    // Opt-in: run `cargo check` after writing the transformed files and roll back on new errors.
+    let validate = args.iter().skip(2).any(|arg| arg == "--validate");
+    inliner_core::run(workspace_path, enable_proc_macros, validate)
 }
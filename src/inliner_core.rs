@@ -2,20 +2,89 @@ use std::{collections::HashMap, fs, path::Path};
 
 use anyhow::Result;
 use hir::{Semantics, db::ExpandDatabase, prettify_macro_expansion};
+use ide::RootDatabase;
 use ide_db::base_db::SourceDatabase;
 use load_cargo;
 use project_model::CargoConfig;
-use syntax::{ast::{self, SourceFile}, AstNode};
+use syntax::{ast::{self, SourceFile}, syntax_editor::Element, ted, AstNode, SyntaxNode};
 use tracing::{debug, info};
 use vfs::FileId;
 
 use crate::util::*;
+use crate::validate_core::{self, CheckerConfig, ValidationReport};
 
-pub fn run(workspace_path: &Path) -> Result<()> {
+// Guards against a macro that (directly or through a cycle of several macros) expands into a
+// call to itself forever; 128 is generous for any legitimate nesting depth C2Rust-instrumented
+// code is expected to produce.
+const MAX_EXPANSION_DEPTH: usize = 128;
+
+// Expand `macro_call` and, unlike a single `parse_or_expand`, keep expanding any macro calls the
+// expansion itself introduces (so callers that inline an already-expanded call don't later find
+// more macro calls left over inside it). Returns the fully expanded, mutable, detached
+// replacement node, or `None` if `macro_call` doesn't resolve to a macro `sema` can expand.
+fn expand_macro_call_fully(
+    db: &RootDatabase,
+    sema: &Semantics<'_, RootDatabase>,
+    macro_call: &ast::MacroCall,
+    fallback_krate: hir::Crate,
+    depth: usize,
+) -> Option<SyntaxNode> {
+    if depth >= MAX_EXPANSION_DEPTH {
+        return None;
+    }
+    let macro_def = sema.to_def(macro_call)?;
+    let span_map = sema.db.expansion_span_map(macro_def.as_macro_file());
+    let expanded = sema.parse_or_expand(macro_def.as_file());
+
+    // A macro re-exported and invoked from a different crate than its caller expands in that
+    // other crate's context, so resolve the crate via this call's own real-file location rather
+    // than assuming the top-level caller's.
+    let krate = sema
+        .file_to_module_def(sema.original_range(macro_call.syntax()).file_id)
+        .map(|module| module.krate())
+        .unwrap_or(fallback_krate);
+
+    let prettified = prettify_macro_expansion(db, expanded.clone(), &span_map, krate.into());
+    let prettified_mut = prettified.clone_for_update();
+
+    // Resolve nested calls against the immutable `expanded` tree (only nodes `sema` parsed
+    // itself can be looked up through it). `prettified`/`prettified_mut` is a reformatted copy of
+    // `expanded` -- reindenting shifts every token's byte offset, so a text range taken from
+    // `expanded` doesn't locate the counterpart node in `prettified_mut`. Prettifying only
+    // changes trivia, never reorders or drops nodes, so the two trees' `MacroCall` descendants
+    // still line up one-to-one in traversal order; pair them up that way instead.
+    let nested_calls_mut = prettified_mut.descendants().filter_map(ast::MacroCall::cast).collect::<Vec<_>>();
+    for (nested_call, nested_call_mut) in expanded
+        .descendants()
+        .filter_map(ast::MacroCall::cast)
+        .collect::<Vec<_>>()
+        .into_iter()
+        .zip(nested_calls_mut)
+    {
+        let Some(nested_expanded) =
+            expand_macro_call_fully(db, sema, &nested_call, krate, depth + 1)
+        else {
+            continue;
+        };
+        ted::replace(nested_call_mut.syntax(), nested_expanded);
+    }
+
+    Some(prettified_mut)
+}
+
+pub fn run(workspace_path: &Path, enable_proc_macros: bool, validate: bool) -> Result<()> {
     let cargo_config = CargoConfig::default();
     let load_cargo_config = load_cargo::LoadCargoConfig {
         load_out_dirs_from_check: false,
-        with_proc_macro_server: load_cargo::ProcMacroServerChoice::None,
+        // Declarative and builtin macros expand fine without a proc-macro server, but
+        // `#[derive(...)]`/attribute/function-like proc macros need one running to resolve at
+        // all (`sema.to_def`/`sema.expand_attr_macro` return `None` otherwise). Opt-in since
+        // starting the server adds real startup cost most workspaces don't need.
+        with_proc_macro_server: if enable_proc_macros {
+            load_cargo::ProcMacroServerChoice::Sysroot
+        } else {
+            load_cargo::ProcMacroServerChoice::None
+        },
         prefill_caches: false,
     };
 
@@ -42,33 +111,98 @@ pub fn run(workspace_path: &Path) -> Result<()> {
             .descendants()
             .filter_map(ast::MacroCall::cast)
         {
-            let Some(macro_def) = sema.to_def(&macro_call) else {
+            let fallback_krate = sema.file_to_module_def(*file_id).unwrap().krate();
+            let Some(prettified) =
+                expand_macro_call_fully(&db, &sema, &macro_call, fallback_krate, 0)
+            else {
                 continue;
             };
-            let span_map = sema.db.expansion_span_map(macro_def.as_macro_file());
-            let expanded = sema.parse_or_expand(macro_def.as_file());
-            let prettified = prettify_macro_expansion(
-                &db,
-                expanded,
-                &span_map,
-                sema.file_to_module_def(*file_id).unwrap().krate().into()
-            );
 
             inlined_count += 1;
             let mut editor = builder_set.make_editor(macro_call.syntax());
             editor.replace(macro_call.syntax(), prettified);
             builder_set.add_file_edits(*file_id, editor);
         }
+
+        if !enable_proc_macros {
+            continue;
+        }
+
+        // Derive and attribute macros aren't `ast::MacroCall` nodes, so they never reach the
+        // loop above even though `sema.to_def` would resolve them given a running proc-macro
+        // server; walk every item and expand whichever of the two kinds of attrs it carries.
+        for item in root.syntax().descendants().filter_map(ast::Item::cast) {
+            if let Some(macro_file) = sema.expand_attr_macro(&item) {
+                let span_map = sema.db.expansion_span_map(macro_file);
+                let expanded = sema.parse_or_expand(macro_file.into());
+                let prettified = prettify_macro_expansion(
+                    &db,
+                    expanded,
+                    &span_map,
+                    sema.file_to_module_def(*file_id).unwrap().krate().into(),
+                );
+
+                inlined_count += 1;
+                let mut editor = builder_set.make_editor(item.syntax());
+                editor.replace(item.syntax(), prettified);
+                builder_set.add_file_edits(*file_id, editor);
+                continue;
+            }
+
+            // A derive macro augments its item rather than replacing it, so each derive path's
+            // expansion is appended after the item instead of swapped in for it.
+            let Some(adt) = ast::Adt::cast(item.syntax().clone()) else {
+                continue;
+            };
+            for attr in item.attrs() {
+                if attr.simple_name().as_deref() != Some("derive") {
+                    continue;
+                }
+                let Some(macro_file) = sema.expand_derive_as_if_attr(&adt, &attr) else {
+                    continue;
+                };
+                let span_map = sema.db.expansion_span_map(macro_file);
+                let expanded = sema.parse_or_expand(macro_file.into());
+                let prettified = prettify_macro_expansion(
+                    &db,
+                    expanded,
+                    &span_map,
+                    sema.file_to_module_def(*file_id).unwrap().krate().into(),
+                );
+
+                inlined_count += 1;
+                let mut editor = builder_set.make_editor(item.syntax());
+                editor.insert_all(
+                    bot_pos(root),
+                    vec![get_empty_line_element_mut(), prettified.syntax_element()],
+                );
+                builder_set.add_file_edits(*file_id, editor);
+            }
+        }
     }
 
     info!(inlined_macros = inlined_count, "Applied inline macro transformations");
 
-    let source_change = builder_set.finish();
+    let (source_change, _provenance) = builder_set.finish();
     apply_source_change(&mut db, &source_change);
 
+    // Baseline the checker against the still-unwritten, pre-transform workspace so `--validate`
+    // can tell an inlining-introduced error apart from one that was already there (C2Rust output
+    // routinely doesn't compile cleanly to begin with).
+    let baseline_report = if validate {
+        validate_core::run_checker(workspace_path, &CheckerConfig::default())?
+    } else {
+        ValidationReport::default()
+    };
+
+    // Snapshot pre-transform contents so a failed `--validate` check can restore them.
+    let mut original_contents = HashMap::new();
     for file_id in syntax_roots.keys() {
         let file_path = vfs.file_path(*file_id);
         let path = file_path.as_path().unwrap();
+        if validate {
+            original_contents.insert(path.to_path_buf(), fs::read_to_string(path)?);
+        }
         let code = db.file_text(*file_id).to_string();
         let code = if code.ends_with("\n") {
             code
@@ -78,5 +212,19 @@ pub fn run(workspace_path: &Path) -> Result<()> {
         fs::write(path, code)?;
     }
 
+    if validate {
+        let report = validate_core::validate_and_maybe_rollback(
+            workspace_path,
+            &CheckerConfig::default(),
+            &original_contents,
+            &baseline_report,
+        )?;
+        info!(
+            errors = report.errors.len(),
+            warnings = report.warnings.len(),
+            "Ran post-inline validation"
+        );
+    }
+
     Ok(())
 }
@@ -1,12 +1,17 @@
 use anyhow::Result;
 use hayroll::{merger_core, util};
 use std::{env, path::Path};
-use tracing::error;
+use tracing::{error, warn};
 
 fn main() -> Result<()> {
     let args: Vec<String> = env::args().collect();
     if args.len() < 3 {
-        error!(usage = %format!("Usage: {} <base-workspace-path> <patch-workspace-path>", args[0]));
+        error!(
+            usage = %format!(
+                "Usage: {} <base-workspace-path> <patch-workspace-path> [--ancestor <path>] [--validate]",
+                args[0]
+            )
+        );
         std::process::exit(1);
     }
 
@@ -14,5 +19,30 @@ fn main() -> Result<()> {
 
     let base_workspace_path = Path::new(&args[1]);
     let patch_workspace_path = Path::new(&args[2]);
-    merger_core::run(base_workspace_path, patch_workspace_path)
+    let rest = &args[3..];
+    // Opt-in: classify each conditional-macro merge three-way against a common ancestor
+    // workspace instead of blindly taking the patch's edit.
+    let ancestor_workspace_path = rest
+        .iter()
+        .position(|arg| arg == "--ancestor")
+        .and_then(|idx| rest.get(idx + 1))
+        .map(Path::new);
+    // Opt-in: run `cargo check` on the base workspace after merging and roll back on new errors.
+    let validate = rest.iter().any(|arg| arg == "--validate");
+
+    let report = merger_core::run(
+        base_workspace_path,
+        patch_workspace_path,
+        ancestor_workspace_path,
+        validate,
+    )?;
+    for conflict in &report.conflicts {
+        warn!(
+            loc = %conflict.loc_begin,
+            base = %conflict.base_code,
+            patch = %conflict.patch_code,
+            "Merge conflict: base and patch changed this conditional macro differently"
+        );
+    }
+    Ok(())
 }
@@ -1,22 +1,314 @@
 use std::{collections::HashMap, fs, path::Path};
 
 use anyhow::Result;
+use hir::{PathResolution, Semantics};
 use ide::RootDatabase;
-use ide_db::base_db::{SourceDatabase, SourceDatabaseFileInputExt};
+use ide_db::base_db::SourceDatabaseFileInputExt;
 use load_cargo;
 use project_model::CargoConfig;
 use syntax::{
-    ast::{self, ElseBranch, HasModuleItem, Item, SourceFile, UseTree},
+    ast::{self, ElseBranch, HasModuleItem, HasVisibility, Item, SourceFile, UseTree},
     syntax_editor::{Element, Position},
-    AstNode,
+    ted, AstNode,
 };
 use tracing::{debug, info};
 use vfs::FileId;
 
 use crate::hayroll_ds::*;
 use crate::util::*;
+use crate::validate_core::{self, CheckerConfig, ValidationReport};
 
-pub fn run(base_workspace_path: &Path, patch_workspace_path: &Path) -> Result<()> {
+// Collect the `use` paths a patch item needs once it's merged into the base file: for every
+// unqualified path inside `item` that resolves (in the patch crate) to a definition outside the
+// item's own module, compute the shortest importable path to that definition, the same way
+// rust-analyzer's extract_module assist resolves references that cross a module boundary.
+// Qualified paths (`foo::bar`) are left alone -- they're either already absolute/`crate`-rooted
+// or refer to something reachable without an import (a sibling item, a prelude type, etc).
+fn needed_use_paths_for_item(
+    sema: &Semantics<'_, RootDatabase>,
+    item: &ast::Item,
+) -> Vec<String> {
+    let Some(origin_module) = sema.scope(item.syntax()).map(|scope| scope.module()) else {
+        return Vec::new();
+    };
+
+    let mut paths = std::collections::BTreeSet::new();
+    for path in item.syntax().descendants().filter_map(ast::Path::cast) {
+        if path.qualifier().is_some() {
+            continue;
+        }
+        let Some(PathResolution::Def(def)) = sema.resolve_path(&path) else {
+            continue;
+        };
+        let Some(def_module) = def.module(sema.db) else {
+            // No owning module (a primitive, builtin, etc.) -- nothing to import.
+            continue;
+        };
+        if def_module == origin_module {
+            // Same module as the item being moved -- no import needed, it'll still resolve.
+            continue;
+        }
+        let Some(mod_path) =
+            origin_module.find_use_path(sema.db, hir::ItemInNs::from(def), false, true)
+        else {
+            continue;
+        };
+        paths.insert(format!("use {};", mod_path));
+    }
+    paths.into_iter().collect()
+}
+
+// Merge `needed_uses` into `base_root`'s existing imports, skipping any whose text exactly matches
+// a top-level `use` declaration already in the file (enough to avoid duplicate imports without
+// reimplementing `ide_db`'s use-tree merging; existing use trees are not merged or collapsed).
+fn merge_needed_uses(
+    base_root: &SourceFile,
+    needed_uses: &std::collections::BTreeSet<String>,
+    editor: &mut syntax::syntax_editor::SyntaxEditor,
+) {
+    if needed_uses.is_empty() {
+        return;
+    }
+    let existing_uses: Vec<String> = base_root
+        .items()
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::Use(use_item) => Some(use_item.syntax().text().to_string()),
+            _ => None,
+        })
+        .collect();
+
+    let mut to_insert: Vec<syntax::SyntaxElement> = Vec::new();
+    for use_text in needed_uses {
+        if existing_uses.iter().any(|existing| existing == use_text) {
+            continue;
+        }
+        let use_item: ast::Use = ast_from_text(use_text);
+        to_insert.push(use_item.syntax().clone().syntax_element());
+        to_insert.push(get_empty_line_element_mut());
+    }
+    if !to_insert.is_empty() {
+        editor.insert_all(top_pos(base_root), to_insert);
+    }
+}
+
+// Normalizes an `if` arm's condition expression (expected to be a `cfg!(...)` call) into a form
+// that compares equal across cosmetic whitespace differences, so the same predicate reached via
+// base and patch is recognized as the same arm even if one side's formatting differs.
+fn normalize_cfg_predicate(condition: &ast::Expr) -> String {
+    condition
+        .syntax()
+        .text()
+        .to_string()
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect()
+}
+
+// A text fingerprint of a CodeRegion's content, cheap enough to compare across three workspaces
+// without diffing syntax trees; two regions with the same fingerprint are considered unchanged
+// relative to each other.
+fn code_region_text(region: &CodeRegion) -> String {
+    region
+        .syntax_element_vec()
+        .iter()
+        .map(|elem| elem.to_string())
+        .collect::<Vec<_>>()
+        .join("")
+}
+
+// A conditional macro's content fingerprint for three-way comparison: placeholders all compare
+// equal to each other (and unequal to any concrete region), so "ancestor placeholder, base/patch
+// both fill it in with the same code" still reads as "changed" on both sides, same as a real edit.
+fn conditional_macro_fingerprint(macro_: &HayrollConditionalMacro) -> String {
+    if macro_.is_placeholder() {
+        "<placeholder>".to_string()
+    } else {
+        code_region_text(&macro_.seed.get_raw_code_region_inside_tag())
+    }
+}
+
+// A genuine three-way conflict: both the base and the patch changed the same conditional-macro
+// region relative to the ancestor, and they changed it differently. Callers decide whether to
+// fail the merge or just surface these for human review; the merge itself still keeps both
+// variants (as an extra `cfg!()` arm) rather than silently dropping one.
+#[derive(Debug, Clone)]
+pub struct MergeConflict {
+    pub loc_begin: String,
+    pub base_code: String,
+    pub patch_code: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MergeReport {
+    pub conflicts: Vec<MergeConflict>,
+}
+
+// How a conditional-macro region compares across ancestor/base/patch.
+enum ThreeWayClassification {
+    // No ancestor match to classify against -- merge exactly as in two-way mode.
+    NoAncestor,
+    KeepBase,
+    TakePatch,
+    KeepEither,
+    Conflict(MergeConflict),
+}
+
+fn classify_three_way(
+    ancestor_macros: &[HayrollConditionalMacro],
+    base_macro: &HayrollConditionalMacro,
+    patch_macro: &HayrollConditionalMacro,
+) -> ThreeWayClassification {
+    let Some(ancestor_macro) = ancestor_macros
+        .iter()
+        .find(|m| m.seed.loc_ref_begin() == base_macro.seed.loc_ref_begin())
+    else {
+        return ThreeWayClassification::NoAncestor;
+    };
+
+    let ancestor_fp = conditional_macro_fingerprint(ancestor_macro);
+    let base_fp = conditional_macro_fingerprint(base_macro);
+    let patch_fp = conditional_macro_fingerprint(patch_macro);
+    let base_changed = base_fp != ancestor_fp;
+    let patch_changed = patch_fp != ancestor_fp;
+
+    match (base_changed, patch_changed) {
+        (false, true) => ThreeWayClassification::TakePatch,
+        (true, false) => ThreeWayClassification::KeepBase,
+        (false, false) => ThreeWayClassification::KeepEither,
+        (true, true) => {
+            if base_fp == patch_fp {
+                ThreeWayClassification::KeepEither
+            } else {
+                ThreeWayClassification::Conflict(MergeConflict {
+                    loc_begin: base_macro.seed.loc_begin(),
+                    base_code: base_fp,
+                    patch_code: patch_fp,
+                })
+            }
+        }
+    }
+}
+
+// Try to merge `patch_code_region_mut` into `base_code_region` for a `(false, false)` conditional-
+// macro pair (both base and patch have concrete code for this region), editing `base_editor` in
+// place. Returns whether a patch variant was actually spliced into the base: `false` for a
+// mismatched-region-type pair (nothing spliced at all) and for an `Expr` pair whose `cfg!()`
+// predicate already matched an existing base arm (that arm's body was overridden in place rather
+// than a new arm being added). Callers must only record `patch_macro.loc_begin()` in the base
+// tag's `mergedVariants` when this returns `true` -- recording it unconditionally would mark a
+// no-op (or an override) as "merged", and the next run's `mergedVariants` guard would then skip
+// ever actually merging that variant in.
+fn merge_conditional_macro_body(
+    base_editor: &mut syntax::syntax_editor::SyntaxEditor,
+    base_code_region: &CodeRegion,
+    patch_code_region_mut: &CodeRegion,
+) -> bool {
+    let mut variant_added = true;
+    match (base_code_region, patch_code_region_mut) {
+        (CodeRegion::Expr(base_expr), CodeRegion::Expr(patch_expr)) => {
+            // base: if cfg!(xx) { val1 } [else if cfg!(yy) { val2 } ...] else { 0 }
+            // patch: if cfg!(zz) { val3 } else { 0 }
+            // merged: if cfg!(xx) { val1 } [else if cfg!(yy) { val2 } ...] else if cfg!(zz) { val3 } else { 0 }
+            // unless `zz` already appears as one of the base arms' predicates, in
+            // which case that arm's body is overridden in place instead, so the
+            // same `cfg!()` value is never tested by two different arms.
+            let base_block = ast::BlockExpr::cast(base_expr.syntax().clone()).unwrap();
+            let base_if =
+                ast::IfExpr::cast(base_block.tail_expr().unwrap().syntax().clone()).unwrap();
+            let patch_block = ast::BlockExpr::cast(patch_expr.syntax().clone()).unwrap();
+            let patch_if =
+                ast::IfExpr::cast(patch_block.tail_expr().unwrap().syntax().clone()).unwrap();
+            let patch_predicate = normalize_cfg_predicate(&patch_if.condition().unwrap());
+
+            let mut existing_arm_with_same_predicate: Option<ast::IfExpr> = None;
+            let mut else_branch = base_if.else_branch().unwrap();
+            let mut current_if = base_if.clone();
+            loop {
+                if normalize_cfg_predicate(&current_if.condition().unwrap()) == patch_predicate {
+                    existing_arm_with_same_predicate = Some(current_if.clone());
+                }
+                match else_branch {
+                    ElseBranch::IfExpr(else_if) => {
+                        // There is no if without else branch in cfg expr, so
+                        // unwrap is safe.
+                        else_branch = else_if.else_branch().unwrap();
+                        current_if = else_if;
+                    }
+                    ElseBranch::Block(_) => break,
+                }
+            }
+            let last_block = match else_branch {
+                ElseBranch::Block(block) => block,
+                ElseBranch::IfExpr(_) => unreachable!(), // loop above only breaks on a Block
+            };
+
+            if let Some(existing_arm) = existing_arm_with_same_predicate {
+                base_editor.replace(
+                    existing_arm.then_branch().unwrap().syntax(),
+                    patch_if.then_branch().unwrap().syntax(),
+                );
+                variant_added = false;
+            } else {
+                base_editor.replace(last_block.syntax(), patch_if.syntax());
+            }
+        }
+        (CodeRegion::Stmts { .. }, CodeRegion::Stmts { .. }) => {
+            let mut patch_stmts_nodes = patch_code_region_mut.syntax_element_vec();
+            // Put an empty line before the inserted stmts to make it look better
+            patch_stmts_nodes.insert(0, get_empty_line_element_mut());
+            base_editor.insert_all(base_code_region.position_after(), patch_stmts_nodes);
+        }
+        (CodeRegion::Decls(_), CodeRegion::Decls(_)) => {
+            // We will merge all top-level declarations later anyways
+            // So no need to do anything here
+        }
+        _ => {
+            // Mismatched types, cannot merge -- nothing was actually spliced into
+            // the base, so don't record the patch variant as merged either.
+            info!("Mismatched types between base and patch code regions, cannot merge");
+            variant_added = false;
+        }
+    }
+    variant_added
+}
+
+// A patch item moved into a different file needs to be at least crate-visible, or the base
+// module's other files (and the merger's own cross-file item dedup on the next run) won't be
+// able to see it; items that are already `pub`/`pub(...)` are left untouched.
+fn ensure_crate_visible(item: &ast::Item) {
+    let has_vis = match item {
+        Item::Fn(it) => it.visibility().is_some(),
+        Item::Struct(it) => it.visibility().is_some(),
+        Item::Enum(it) => it.visibility().is_some(),
+        Item::Const(it) => it.visibility().is_some(),
+        Item::Static(it) => it.visibility().is_some(),
+        Item::TypeAlias(it) => it.visibility().is_some(),
+        Item::Trait(it) => it.visibility().is_some(),
+        _ => return,
+    };
+    if has_vis {
+        return;
+    }
+    let vis: ast::Visibility = ast_from_text("pub(crate)");
+    let Some(first_token) = item.syntax().first_token() else {
+        return;
+    };
+    ted::insert_all(
+        ted::Position::before(&first_token),
+        vec![
+            vis.syntax().clone().syntax_element(),
+            syntax::NodeOrToken::Token(ast::make::tokens::whitespace(" ")),
+        ],
+    );
+}
+
+pub fn run(
+    base_workspace_path: &Path,
+    patch_workspace_path: &Path,
+    ancestor_workspace_path: Option<&Path>,
+    validate: bool,
+) -> Result<MergeReport> {
     let cargo_config = CargoConfig::default();
     let load_cargo_config = load_cargo::LoadCargoConfig {
         load_out_dirs_from_check: false,
@@ -39,7 +331,9 @@ pub fn run(base_workspace_path: &Path, patch_workspace_path: &Path) -> Result<()
     for (file_id, _root) in &base_syntax_roots {
         debug!(file = %base_vfs.file_path(*file_id), "base workspace file");
     }
-    let base_hayroll_seeds = extract_hayroll_seeds_from_syntax_roots(&base_syntax_roots);
+    let (base_hayroll_seeds, base_seed_diagnostics) =
+        extract_hayroll_seeds_from_syntax_roots(&base_syntax_roots);
+    log_hayroll_diagnostics(&base_vfs, &base_seed_diagnostics);
     let base_hayroll_conditional_macros: Vec<HayrollConditionalMacro> = base_hayroll_seeds
         .iter()
         .filter(|seed| seed.is_conditional())
@@ -53,6 +347,7 @@ pub fn run(base_workspace_path: &Path, patch_workspace_path: &Path) -> Result<()
         &|_| {},
     )?;
     let patch_syntax_roots: HashMap<FileId, SourceFile> = collect_syntax_roots_from_db(&patch_db);
+    let patch_sema = Semantics::new(&patch_db);
     let mut patch_builder_set = SourceChangeBuilderSet::from_syntax_roots(&patch_syntax_roots);
     info!(
         found_files = patch_syntax_roots.len(),
@@ -61,13 +356,47 @@ pub fn run(base_workspace_path: &Path, patch_workspace_path: &Path) -> Result<()
     for (file_id, _root) in &patch_syntax_roots {
         debug!(file = %patch_vfs.file_path(*file_id), "patch workspace file");
     }
-    let patch_hayroll_seeds = extract_hayroll_seeds_from_syntax_roots(&patch_syntax_roots);
+    let (patch_hayroll_seeds, patch_seed_diagnostics) =
+        extract_hayroll_seeds_from_syntax_roots(&patch_syntax_roots);
+    log_hayroll_diagnostics(&patch_vfs, &patch_seed_diagnostics);
     let patch_hayroll_conditional_macros: Vec<HayrollConditionalMacro> = patch_hayroll_seeds
         .iter()
         .filter(|seed| seed.is_conditional())
         .map(|seed| HayrollConditionalMacro { seed: seed.clone() })
         .collect();
 
+    // When given, load the common-ancestor workspace so conditional-macro merges can be
+    // classified three-way (unchanged-in-base/patch vs. genuine conflict) instead of blindly
+    // pairing base and patch and always taking the patch's edit.
+    let ancestor_hayroll_conditional_macros: Vec<HayrollConditionalMacro> =
+        if let Some(ancestor_workspace_path) = ancestor_workspace_path {
+            let (ancestor_db, ancestor_vfs, _proc_macro) = load_cargo::load_workspace_at(
+                ancestor_workspace_path,
+                &cargo_config,
+                &load_cargo_config,
+                &|_| {},
+            )?;
+            let ancestor_syntax_roots: HashMap<FileId, SourceFile> =
+                collect_syntax_roots_from_db(&ancestor_db);
+            info!(
+                found_files = ancestor_syntax_roots.len(),
+                "Found Rust files in the ancestor workspace"
+            );
+            for (file_id, _root) in &ancestor_syntax_roots {
+                debug!(file = %ancestor_vfs.file_path(*file_id), "ancestor workspace file");
+            }
+            let (ancestor_hayroll_seeds, ancestor_seed_diagnostics) =
+                extract_hayroll_seeds_from_syntax_roots(&ancestor_syntax_roots);
+            log_hayroll_diagnostics(&ancestor_vfs, &ancestor_seed_diagnostics);
+            ancestor_hayroll_seeds
+                .iter()
+                .filter(|seed| seed.is_conditional())
+                .map(|seed| HayrollConditionalMacro { seed: seed.clone() })
+                .collect()
+        } else {
+            Vec::new()
+        };
+
     // In base_hayroll_conditional_macros, some may share the same loc_ref_begin()
     // Create a list that only keeps one of them (the first one encountered)
     let base_hayroll_conditional_macros_unique_ref: Vec<HayrollConditionalMacro> =
@@ -99,12 +428,37 @@ pub fn run(base_workspace_path: &Path, patch_workspace_path: &Path) -> Result<()
             })
             .collect();
 
+    let mut merge_report = MergeReport::default();
+
     for (base_macro, patch_macro) in paired_conditional_macros.iter() {
         print!(
             "Processing conditonal macro pair {} and {}\n",
             base_macro.seed.loc_begin(),
             patch_macro.seed.loc_begin()
         );
+
+        match classify_three_way(&ancestor_hayroll_conditional_macros, base_macro, patch_macro) {
+            ThreeWayClassification::NoAncestor => {}
+            ThreeWayClassification::KeepBase => {
+                info!("Three-way merge: patch unchanged from ancestor, keeping base as-is");
+                continue;
+            }
+            ThreeWayClassification::TakePatch | ThreeWayClassification::KeepEither => {
+                // Either base never diverged from the ancestor (so the existing two-way logic
+                // below just needs to bring the patch in), or both sides changed identically (so
+                // running it again is a harmless no-op thanks to the `mergedVariants` check).
+            }
+            ThreeWayClassification::Conflict(conflict) => {
+                info!(
+                    loc = %conflict.loc_begin,
+                    "Three-way merge: base and patch changed this region differently, recording conflict and keeping both variants"
+                );
+                merge_report.conflicts.push(conflict);
+                // Fall through to the existing merge below, which appends the patch's variant as
+                // an additional `cfg!()` arm instead of discarding either side.
+            }
+        }
+
         let decl_root = base_syntax_roots.get(&base_macro.seed.file_id()).unwrap();
         let mut base_editor = base_builder_set.make_editor(decl_root.syntax());
         match (base_macro.is_placeholder(), patch_macro.is_placeholder()) {
@@ -155,58 +509,23 @@ pub fn run(base_workspace_path: &Path, patch_workspace_path: &Path) -> Result<()
                     let patch_code_region = patch_macro.seed.get_raw_code_region_inside_tag();
                     let patch_code_region_mut =
                         patch_code_region.make_mut_with_builder_set(&mut patch_builder_set);
-                    match (&base_code_region, &patch_code_region_mut) {
-                        (CodeRegion::Expr(base_expr), CodeRegion::Expr(patch_expr)) => {
-                            // base: if cfg!(xx) { val1 } [else if cfg!(yy) { val2 } ...] else { 0 }
-                            // patch: if cfg!(zz) { val3 } else { 0 }
-                            // merged: if cfg!(xx) { val1 } [else if cfg!(yy) { val2 } ...] else if cfg!(zz) { val3 } else { 0 }
-                            let base_block =
-                                ast::BlockExpr::cast(base_expr.syntax().clone()).unwrap();
-                            let base_if =
-                                ast::IfExpr::cast(base_block.tail_expr().unwrap().syntax().clone())
-                                    .unwrap();
-                            let patch_block =
-                                ast::BlockExpr::cast(patch_expr.syntax().clone()).unwrap();
-                            let patch_if = ast::IfExpr::cast(
-                                patch_block.tail_expr().unwrap().syntax().clone(),
-                            )
-                            .unwrap();
-
-                            let mut else_branch = base_if.else_branch().unwrap();
-                            while let ElseBranch::IfExpr(else_if) = else_branch {
-                                // There is no if without else branch in cfg expr, so unwrap is safe
-                                let next_else = else_if.else_branch().unwrap();
-                                else_branch = next_else;
-                            }
-                            let last_block = match else_branch {
-                                ElseBranch::Block(block) => block,
-                                ElseBranch::IfExpr(_) => unreachable!(), // because of the while let above
-                            };
-                            base_editor.replace(last_block.syntax(), patch_if.syntax());
-                        }
-                        (CodeRegion::Stmts { .. }, CodeRegion::Stmts { .. }) => {
-                            let mut patch_stmts_nodes = patch_code_region_mut.syntax_element_vec();
-                            // Put an empty line before the inserted stmts to make it look better
-                            patch_stmts_nodes.insert(0, get_empty_line_element_mut());
-                            base_editor
-                                .insert_all(base_code_region.position_after(), patch_stmts_nodes);
-                        }
-                        (CodeRegion::Decls(_), CodeRegion::Decls(_)) => {
-                            // We will merge all top-level declarations later anyways
-                            // So no need to do anything here
-                        }
-                        _ => {
-                            // Mismatched types, cannot merge
-                            info!("Mismatched types between base and patch code regions, cannot merge");
-                        }
+                    let variant_added = merge_conditional_macro_body(
+                        &mut base_editor,
+                        &base_code_region,
+                        &patch_code_region_mut,
+                    );
+                    // Update the HayrollTag in the replaced code to append the merged variant,
+                    // unless nothing was actually spliced in (a mismatched-type pair) or the Expr
+                    // case overrode an existing arm rather than adding one -- then `mergedVariants`
+                    // should stay exactly as accurate as the cfg chain is.
+                    if variant_added {
+                        let new_variant = patch_macro.loc_begin();
+                        let new_literal = base_macro
+                            .with_appended_merged_variants(&new_variant)
+                            .clone_for_update();
+                        let old_literal = base_macro.seed.first_tag().literal.clone();
+                        base_editor.replace(old_literal.syntax(), new_literal.syntax());
                     }
-                    // Update the HayrollTag in the replaced code to append the merged variant
-                    let new_variant = patch_macro.loc_begin();
-                    let new_literal = base_macro
-                        .with_appended_merged_variants(&new_variant)
-                        .clone_for_update();
-                    let old_literal = base_macro.seed.first_tag().literal.clone();
-                    base_editor.replace(old_literal.syntax(), new_literal.syntax());
                 }
             }
             (true, true) => {
@@ -315,6 +634,7 @@ pub fn run(base_workspace_path: &Path, patch_workspace_path: &Path) -> Result<()
         // Collect items to insert, categorized by placement
         let mut to_top: Vec<syntax::SyntaxElement> = Vec::new();
         let mut to_bot: Vec<syntax::SyntaxElement> = Vec::new();
+        let mut needed_uses: std::collections::BTreeSet<String> = std::collections::BTreeSet::new();
 
         for p_item in patch_root.items() {
             let Some(name) = item_name(&p_item) else {
@@ -325,7 +645,13 @@ pub fn run(base_workspace_path: &Path, patch_workspace_path: &Path) -> Result<()
                 continue;
             }
 
-            let elem = p_item.syntax().clone_for_update().syntax_element();
+            // Resolve the item's references against the patch crate (where it still has its
+            // original module context) before it's detached and loses that context.
+            needed_uses.extend(needed_use_paths_for_item(&patch_sema, &p_item));
+
+            let item_mut = Item::cast(p_item.syntax().clone_for_update()).unwrap();
+            ensure_crate_visible(&item_mut);
+            let elem = item_mut.syntax().clone().syntax_element();
             if is_macro_def(&p_item) {
                 // insert macro at top (after file attrs), keep an empty line after
                 to_top.push(elem);
@@ -339,6 +665,7 @@ pub fn run(base_workspace_path: &Path, patch_workspace_path: &Path) -> Result<()
 
         if !to_top.is_empty() || !to_bot.is_empty() {
             let mut editor = base_builder_set.make_editor(base_root.syntax());
+            merge_needed_uses(base_root, &needed_uses, &mut editor);
             if !to_top.is_empty() {
                 let top = top_pos(base_root);
                 editor.insert_all(top, to_top);
@@ -399,38 +726,101 @@ pub fn run(base_workspace_path: &Path, patch_workspace_path: &Path) -> Result<()
     }
 
     // Finalize edits from the single global builder
-    let source_change = base_builder_set.finish();
+    let (source_change, _provenance) = base_builder_set.finish();
     // Apply edits to the in-memory DB via file_text inputs
     apply_source_change(&mut base_db, &source_change);
 
-    // Write back all modified files to disk
+    // Baseline the checker against the still-unwritten, pre-merge workspace so `--validate` can
+    // tell a merge-introduced error apart from one that was already there (C2Rust output routinely
+    // doesn't compile cleanly to begin with).
+    let baseline_report = if validate {
+        validate_core::run_checker(base_workspace_path, &CheckerConfig::default())?
+    } else {
+        ValidationReport::default()
+    };
+
+    // Write back all modified files to disk, snapshotting pre-merge contents first so a failed
+    // `--validate` check can restore them.
+    let mut original_contents = HashMap::new();
     for file_id in base_syntax_roots.keys() {
         let file_path = base_vfs.file_path(*file_id);
+        let path = file_path.as_path().unwrap();
+        if validate {
+            original_contents.insert(path.to_path_buf(), fs::read_to_string(path)?);
+        }
         let code = base_db.file_text(*file_id).to_string();
         let code = if code.ends_with("\n") {
             code
         } else {
             code + "\n"
         };
-        let path = file_path.as_path().unwrap();
         fs::write(path, code)?;
     }
 
-    Ok(())
+    if validate {
+        let report = validate_core::validate_and_maybe_rollback(
+            base_workspace_path,
+            &CheckerConfig::default(),
+            &original_contents,
+            &baseline_report,
+        )?;
+        info!(
+            errors = report.errors.len(),
+            warnings = report.warnings.len(),
+            "Ran post-merge validation"
+        );
+    }
+
+    Ok(merge_report)
 }
 
-// Apply the source change to the RootDatabase
-fn apply_source_change(db: &mut RootDatabase, source_change: &ide::SourceChange) {
-    // Best-effort transactional behavior: cancel outstanding queries first.
-    db.request_cancellation();
-
-    // Apply per-file text edits directly to DB inputs.
-    for (file_id, (text_edit, snippet)) in source_change.source_file_edits.iter() {
-        let mut code = db.file_text(*file_id).to_string();
-        text_edit.apply(&mut code);
-        if let Some(snippet) = snippet {
-            snippet.apply(&mut code);
-        }
-        db.set_file_text(*file_id, &code);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syntax::Edition;
+
+    #[test]
+    fn merge_conditional_macro_body_mismatched_types_does_not_add_variant() {
+        // Base's region is an Expr, patch's is a Stmts span -- the kind of mismatch a base/patch
+        // pair can end up with when the original C `#ifdef` arms took genuinely different shapes
+        // (e.g. an expression on one side, a statement sequence on the other).
+        let base_source = "fn f() -> i32 { 1 + 1 }";
+        let base_file = SourceFile::parse(base_source, Edition::Edition2021).tree();
+        let base_fn = base_file.syntax().descendants().find_map(ast::Fn::cast).unwrap();
+        let base_expr = base_fn.body().unwrap().tail_expr().unwrap();
+        let base_code_region = CodeRegion::Expr(base_expr);
+
+        let patch_source = "fn g() { let a = 1; let b = 2; }";
+        let patch_file = SourceFile::parse(patch_source, Edition::Edition2021).tree();
+        let patch_fn = patch_file.syntax().descendants().find_map(ast::Fn::cast).unwrap();
+        let stmt_list = patch_fn.body().unwrap().stmt_list().unwrap();
+        let patch_code_region = CodeRegion::Stmts {
+            parent: stmt_list,
+            range: 0..=1,
+        };
+
+        let patch_file_id = FileId::from_raw(0);
+        let mut patch_syntax_roots = HashMap::new();
+        patch_syntax_roots.insert(patch_file_id, patch_file);
+        let mut patch_builder_set = SourceChangeBuilderSet::from_syntax_roots(&patch_syntax_roots);
+        let patch_code_region_mut =
+            patch_code_region.make_mut_with_builder_set(&mut patch_builder_set);
+
+        let mut base_editor = syntax::syntax_editor::SyntaxEditor::new(base_file.syntax().clone());
+
+        let variant_added = merge_conditional_macro_body(
+            &mut base_editor,
+            &base_code_region,
+            &patch_code_region_mut,
+        );
+
+        // The bug this guards against: marking a no-op merge as done in `mergedVariants` would
+        // silently and permanently drop the patch's content, since the next run's `mergedVariants`
+        // check treats it as already merged.
+        assert!(
+            !variant_added,
+            "a mismatched-type base/patch pair spliced nothing into the base and must not be \
+             reported as a merged variant"
+        );
     }
 }
@@ -1,17 +1,88 @@
-use anyhow::Result;
-use hayroll::{reaper_core, util};
-use std::{env, path::Path};
+use anyhow::{anyhow, Result};
+use hayroll::{
+    hayroll_ds::{DiagnosticOutputFormat, ExtractMode, PremiseDictionary},
+    reaper_core, util,
+};
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
 use tracing::error;
 
 fn main() -> Result<()> {
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
-        error!(usage = %format!("Usage: {} <workspace-path>", args[0]));
+        error!(usage = %format!("Usage: {} <workspace-path> [--macros-module] [--hayroll-module] [--compile-time-conditionals] [--diagnostics-format=human|json] [--premises=file1.premises,file2.premises] [--extract-mode=resilient|strict]", args[0]));
         std::process::exit(1);
     }
 
     util::init_logging();
 
     let workspace_path = Path::new(&args[1]);
-    reaper_core::run(workspace_path)
+    // Opt-in: collect every generated macro/fn definition into one `hayroll_macros.rs` module
+    // instead of duplicating each one inline at its first invocation's file.
+    let emit_dedicated_macros_module = args.iter().skip(2).any(|arg| arg == "--macros-module");
+    // Opt-in: fold each file's own generated definitions into an inline `mod hayroll { ... }`
+    // block instead of scattering them at file scope.
+    let emit_hayroll_module = args.iter().skip(2).any(|arg| arg == "--hayroll-module");
+    // Opt-in: lower conditional `Expr` seeds to attribute-selected branches instead of a runtime
+    // `cfg!()` check, so the inactive branch is never compiled.
+    let compile_time_conditional_branches = args
+        .iter()
+        .skip(2)
+        .any(|arg| arg == "--compile-time-conditionals");
+    // Selects how tag pairing/matching diagnostics are reported: the default `human` form keeps
+    // logging through `tracing`, while `json` prints one rustc-style JSON object per diagnostic on
+    // stdout for a GitHub Actions problem matcher or an editor's LSP-style consumer.
+    let diagnostic_format = match args
+        .iter()
+        .skip(2)
+        .find_map(|arg| arg.strip_prefix("--diagnostics-format="))
+    {
+        Some("json") => DiagnosticOutputFormat::Json,
+        Some("human") | None => DiagnosticOutputFormat::Human,
+        Some(other) => {
+            error!(format = %other, "unrecognized --diagnostics-format value; expected human or json");
+            std::process::exit(1);
+        }
+    };
+    // Optional layered alias dictionary for premise cfg fragments (see `PremiseDictionary`):
+    // comma-separated `.premises` file paths, merged in order so a later file's aliases override
+    // an earlier file's.
+    let premise_dict = match args
+        .iter()
+        .skip(2)
+        .find_map(|arg| arg.strip_prefix("--premises="))
+    {
+        Some(paths) => {
+            let paths: Vec<PathBuf> = paths.split(',').map(PathBuf::from).collect();
+            PremiseDictionary::load_files(&paths).map_err(|e| anyhow!(e))?
+        }
+        None => PremiseDictionary::new(),
+    };
+    // Selects how a tag-pairing/arg-matching problem is handled during extraction: the default
+    // `resilient` mode records it as a diagnostic and skips just the malformed seed/arg, while
+    // `strict` panics on the first one -- for a CI run that would rather fail fast on a single bad
+    // site than risk silently dropping it.
+    let extract_mode = match args
+        .iter()
+        .skip(2)
+        .find_map(|arg| arg.strip_prefix("--extract-mode="))
+    {
+        Some("resilient") | None => ExtractMode::Resilient,
+        Some("strict") => ExtractMode::Strict,
+        Some(other) => {
+            error!(mode = %other, "unrecognized --extract-mode value; expected resilient or strict");
+            std::process::exit(1);
+        }
+    };
+    reaper_core::run(
+        workspace_path,
+        emit_dedicated_macros_module,
+        emit_hayroll_module,
+        compile_time_conditional_branches,
+        diagnostic_format,
+        &premise_dict,
+        extract_mode,
+    )
 }
@@ -1,21 +1,78 @@
-use std::{collections::HashMap, fs, path::Path};
+use std::{collections::{BTreeSet, HashMap, HashSet}, fs, path::Path};
 
 use anyhow::Result;
-use ide_db::base_db::SourceDatabase;
+use hir::{PathResolution, Semantics};
+use ide::RootDatabase;
+use ide_db::base_db::{AnchoredPathBuf, SourceDatabase};
 use ide_db::source_change::TreeMutator;
 use load_cargo;
 use project_model::CargoConfig;
-use syntax::ast::{ElseBranch, IfExpr, Item, ReturnExpr, Stmt};
+use syntax::ast::{self, ElseBranch, HasVisibility, IfExpr, Item, MacroCall, ReturnExpr, Stmt};
 use syntax::syntax_editor::Position;
 use syntax::ted;
-use syntax::{ast::SourceFile, syntax_editor::Element, AstNode, SyntaxElement};
+use syntax::{ast::SourceFile, syntax_editor::Element, AstNode, SyntaxElement, SyntaxNode};
 use tracing::{debug, info, warn};
 use vfs::FileId;
 
 use crate::hayroll_ds::*;
 use crate::util::*;
 
-pub fn run(workspace_path: &Path) -> Result<()> {
+// Where to splice a regenerated end tag after a single divergent exit found by
+// `return_anchors_in_range`: either the `return`'s own wrapping statement (the common case,
+// however deeply nested inside if/match arms), or -- when C2Rust instead left it as a block's bare
+// tail expression with no trailing `;` -- the tail expression itself, which needs wrapping before
+// anything can be inserted after it.
+enum ReturnAnchor {
+    Stmt(Stmt),
+    BareTailExpr(ReturnExpr),
+}
+
+// Every point strictly after the begin tag statement at `range.start()` where control flow can
+// leave the tagged region via a `return`, nested arbitrarily deep inside if/match arms. `range.end()`
+// isn't usable here -- the original end tag was already eaten by the early return this is looking
+// for, so `range` is always the single-point `start..=start` stand-in built from the begin tag --
+// so the scan stops at the next statement that is itself a Hayroll tag instead, to avoid attaching
+// a spurious cloned end tag after an unrelated `return` further down the same function.
+fn return_anchors_in_range(
+    parent: &ast::StmtList,
+    range: &std::ops::RangeInclusive<usize>,
+) -> Vec<ReturnAnchor> {
+    parent
+        .statements()
+        .enumerate()
+        .skip(*range.start() + 1)
+        .take_while(|(_, stmt)| !stmt_is_hayroll_tag(stmt))
+        .flat_map(|(_, stmt)| stmt.syntax().descendants().filter_map(ReturnExpr::cast))
+        .map(|return_expr| {
+            for ancestor in return_expr.syntax().ancestors() {
+                if let Some(stmt) = Stmt::cast(ancestor.clone()) {
+                    return ReturnAnchor::Stmt(stmt);
+                }
+                if let Some(stmt_list) = ast::StmtList::cast(ancestor) {
+                    if stmt_list.tail_expr().as_ref().map(|e| e.syntax())
+                        == Some(return_expr.syntax())
+                    {
+                        return ReturnAnchor::BareTailExpr(return_expr.clone());
+                    }
+                }
+            }
+            unreachable!(
+                "return expression {} is neither a statement nor a block's tail expression",
+                return_expr.syntax()
+            );
+        })
+        .collect()
+}
+
+pub fn run(
+    workspace_path: &Path,
+    emit_dedicated_macros_module: bool,
+    emit_hayroll_module: bool,
+    compile_time_conditional_branches: bool,
+    diagnostic_format: DiagnosticOutputFormat,
+    premise_dict: &PremiseDictionary,
+    extract_mode: ExtractMode,
+) -> Result<()> {
     // Record the start time
     let cargo_config = CargoConfig::default();
     let load_cargo_config = load_cargo::LoadCargoConfig {
@@ -45,41 +102,52 @@ pub fn run(workspace_path: &Path) -> Result<()> {
         let CodeRegion::Stmts { parent, range } = code_region else {
             unreachable!()
         };
-        let first_return = parent
-            .statements()
-            .enumerate()
-            .filter(|(i, _stmt)| *i >= *range.start())
-            .find(|(_i, stmt)| match stmt {
-                Stmt::ExprStmt(expr_stmt) => expr_stmt
-                    .expr()
-                    .map_or(false, |e| ReturnExpr::can_cast(e.syntax().kind())),
-                _ => false,
-            })
-            .map(|(_i, stmt)| stmt)
-            .expect(
-                format!(
-                    "Expected to find a return statement for Hayroll tag: {}",
-                    tag.tag
-                )
-                .as_str(),
-            );
-        let after_return_pos = Position::after(&first_return.syntax());
-        let end_literal_mut = tag.with_updated_begin(false).clone_for_update();
         let begin_stmt = parent.statements().nth(*range.start()).unwrap();
-        let begin_literal = &tag.literal;
-        let tree_mutator = TreeMutator::new(begin_stmt.syntax());
-        let begin_stmt_mut = tree_mutator.make_mut(&begin_stmt);
-        let begin_literal_mut = tree_mutator.make_syntax_mut(begin_literal.syntax());
-        ted::replace(begin_literal_mut, end_literal_mut.syntax());
-        editor.insert(
-            after_return_pos,
-            begin_stmt_mut.syntax().syntax_element().clone(),
+        let return_anchors = return_anchors_in_range(&parent, &range);
+        assert!(
+            !return_anchors.is_empty(),
+            "Expected to find at least one return statement for Hayroll tag: {:?}",
+            tag.tag
         );
+        for anchor in return_anchors {
+            // A fresh mutable clone of the begin-tag statement, with its embedded literal flipped
+            // to the end-tag form -- minted separately for every divergent exit so each one gets
+            // its own end tag, rather than only the single exit the original code assumed.
+            let end_literal_mut = tag.with_updated_begin(false).clone_for_update();
+            let tree_mutator = TreeMutator::new(begin_stmt.syntax());
+            let begin_stmt_mut = tree_mutator.make_mut(&begin_stmt);
+            let begin_literal_mut = tree_mutator.make_syntax_mut(tag.literal.syntax());
+            ted::replace(begin_literal_mut, end_literal_mut.syntax());
+            let end_stmt_clone = begin_stmt_mut;
+
+            match anchor {
+                ReturnAnchor::Stmt(stmt) => {
+                    editor.insert(
+                        Position::after(&stmt.syntax()),
+                        end_stmt_clone.syntax().syntax_element().clone(),
+                    );
+                }
+                ReturnAnchor::BareTailExpr(return_expr) => {
+                    // `return_expr` is itself a block's tail expression (no trailing `;`), e.g. the
+                    // `return x` arm of `if c { return x } else { y }` flattened in by C2Rust, so
+                    // there's no statement to insert the end tag after. Replace the bare tail
+                    // expression with a nested block that sequences the original (diverging) return
+                    // ahead of the end-tag statement; the nested block still only ever evaluates via
+                    // the `return`, so it stays well-typed in whatever position the tail expr was.
+                    let return_text = return_expr.syntax().text().to_string();
+                    let end_tag_text = end_stmt_clone.syntax().text().to_string();
+                    let wrapped_text = format!("{{ {}; {} }}", return_text, end_tag_text);
+                    let wrapped_block: ast::BlockExpr =
+                        prettify_generated_node(ast_from_text(&wrapped_text)).clone_for_update();
+                    editor.replace(return_expr.syntax(), wrapped_block.syntax());
+                }
+            }
+        }
         builder_set.add_file_edits(file_id, editor);
     }
 
     // Finalize edits from the single global builder
-    let source_change = builder_set.finish();
+    let (source_change, _provenance) = builder_set.finish();
     // Apply edits to the in-memory DB via file_text inputs
     apply_source_change(&mut db, &source_change);
 
@@ -96,11 +164,28 @@ pub fn run(workspace_path: &Path) -> Result<()> {
         debug!(file = %vfs.file_path(*file_id), "workspace file");
     }
 
+    // Built once per file so the per-cluster loop below can look up a `Decls` seed's enclosing
+    // items with a binary search instead of `find_items_in_range`'s linear rescan -- clusters are
+    // iterated in `hayroll_macro_db.map` order, not grouped by file, so without this every
+    // invocation sharing a declaration file would otherwise re-walk that file's items from scratch.
+    let src_loc_indices: HashMap<FileId, SrcLocIndex> = syntax_roots
+        .iter()
+        .map(|(file_id, root)| (*file_id, SrcLocIndex::build(root)))
+        .collect();
+
     // We are using the SyntaxEditor paradigm, so we need only one builder
     // Create a single global builder; pick any file id
-    let hayroll_seeds = extract_hayroll_seeds_from_syntax_roots(&syntax_roots);
-    let hayroll_macro_invs = extract_hayroll_macro_invs_from_seeds(&hayroll_seeds);
+    let (hayroll_seeds, seed_diagnostics) =
+        extract_hayroll_seeds_from_syntax_roots_with_mode(&syntax_roots, extract_mode);
+    emit_hayroll_diagnostics(diagnostic_format, &vfs, &syntax_roots, &seed_diagnostics);
+    let (hayroll_macro_invs, inv_diagnostics) =
+        extract_hayroll_macro_invs_from_seeds_with_mode(&hayroll_seeds, extract_mode);
+    emit_hayroll_diagnostics(diagnostic_format, &vfs, &syntax_roots, &inv_diagnostics);
     let hayroll_macro_db = HayrollMacroDB::from_hayroll_macro_invs(&hayroll_macro_invs);
+
+    // Needed to resolve the concrete crate name a generated definition's declaration file belongs
+    // to, for stripping any `$crate` left over from text sourced from a macro expansion.
+    let sema_for_crate_names = Semantics::new(&db);
     
     // Find out which macro invocations have name duplicates
     // i.e. has another macro invocation with the same name but different locRefBegin
@@ -119,9 +204,94 @@ pub fn run(workspace_path: &Path) -> Result<()> {
             .collect()
     };
 
+    // Round-trip checks to run once the edits below are applied: each entry asserts that calling
+    // the freshly generated macro at one of its invocations re-expands back to that invocation's
+    // original region.
+    let mut round_trip_checks: Vec<RoundTripCheck> = Vec::new();
+
+    // When `emit_dedicated_macros_module` is set, every generated definition is collected here
+    // instead of being inserted inline into its declaration file; `dedicated_module_anchor` pins
+    // the file whose crate root the generated module is created next to (the first cluster's
+    // declaration file's crate, arbitrarily -- this mode assumes a single-crate workspace), and
+    // `dedicated_module_consumers` accumulates every file that needs a `use` import added once the
+    // module exists, since it no longer carries the definition it used to have inline.
+    let mut dedicated_module_defs: Vec<String> = Vec::new();
+    let mut dedicated_module_anchor: Option<FileId> = None;
+    let mut dedicated_module_consumers: HashSet<FileId> = HashSet::new();
+
+    // When `emit_hayroll_module` is set, every generated definition for a file is collected here
+    // instead of being inserted inline at file scope, and folded into one `mod hayroll { ... }`
+    // block per file once the per-cluster loop below finishes. `hayroll_module_names` is
+    // precomputed so reference classification (internal to the new module vs. still out in the
+    // enclosing file) doesn't depend on loop iteration order.
+    let hayroll_module_names: HashSet<String> = if emit_hayroll_module {
+        hayroll_macro_db.map.values().map(|cluster| cluster.name()).collect()
+    } else {
+        HashSet::new()
+    };
+    let mut hayroll_module_defs_by_file: HashMap<FileId, Vec<String>> = HashMap::new();
+
+    // Declaration sites with more than one distinct-signature cluster (the same C macro
+    // instantiated at e.g. both `int` and `long`) that are nonetheless unifiable into one generic
+    // `fn` -- see `unify_fn_clusters`. Absent entries fall through to the ordinary per-cluster
+    // `can_be_fn`/`fn_` decision below, exactly as before this existed.
+    let generic_fns = hayroll_macro_db.unify_fn_by_loc_decl(Some(&sema_for_crate_names));
+    // Several clusters share a `loc_ref_begin` present in `generic_fns`, but the shared definition
+    // only needs inserting into the declaration file once.
+    let mut generic_fn_inserted: HashSet<String> = HashSet::new();
+
+    // The `macro_rules!` analogue of `generic_fns` above: declaration sites with more than one
+    // structurally-compatible-but-not-type-compatible cluster (so `can_be_fn` failed and they can't
+    // share a generic `fn` either) are merged into one `macro_rules!` with one arm per cluster --
+    // see `HayrollMacroDB::merge_macro_rules_by_loc_decl`. A declaration site absent from this map
+    // either has only one cluster, or has a cluster that took the `can_be_fn`/`generic_fns` path
+    // instead, and falls through to the ordinary per-cluster `macro_rules()` call below exactly as
+    // before this existed.
+    let merged_macro_rules = hayroll_macro_db.merge_macro_rules_by_loc_decl(Some(&sema_for_crate_names));
+    let mut merged_macro_rules_inserted: HashSet<String> = HashSet::new();
+
+    // A macro invocation nested inside another (e.g. `signbit!` inside `__raise_overflowf`) must be
+    // reconstructed before its enclosing invocation, or the outer invocation's own region lookup
+    // would have to climb through an as-yet-nonexistent macro call. `hayroll_macro_db.map` gives no
+    // such ordering on its own (HashMap iteration order is arbitrary), so pair every file's begin/
+    // end tags into a containment tree and use its depths to process the deepest-nested clusters
+    // first instead of relying on iteration order happening to already be bottom-up.
+    let nesting_depths: HashMap<String, usize> = {
+        let mut tags_by_file: HashMap<FileId, Vec<HayrollTag>> = HashMap::new();
+        for tag in collect_hayroll_tags_from_syntax_roots(&syntax_roots) {
+            tags_by_file.entry(tag.file_id()).or_default().push(tag);
+        }
+        let mut region_diagnostics: Vec<HayrollDiagnostic> = Vec::new();
+        let depths = tags_by_file
+            .into_iter()
+            .flat_map(|(file_id, tags)| {
+                let (roots, diagnostics) = pair_macro_regions(file_id, &tags);
+                region_diagnostics.extend(diagnostics);
+                MacroRegion::nesting_depths(&roots)
+            })
+            .collect();
+        emit_hayroll_diagnostics(diagnostic_format, &vfs, &syntax_roots, &region_diagnostics);
+        depths
+    };
+    let mut ordered_clusters: Vec<(&(String, String), &HayrollMacroCluster)> =
+        hayroll_macro_db.map.iter().collect();
+    ordered_clusters.sort_by_key(|(_, cluster)| {
+        std::cmp::Reverse(
+            cluster
+                .invocations
+                .iter()
+                .map(|inv| *nesting_depths.get(&inv.hayroll_tag().tag.loc_inv).unwrap_or(&0))
+                .max()
+                .unwrap_or(0),
+        )
+    });
+    // Per (declaration file, call spelling) count of the round-trip checks queued so far, shared
+    // by every cluster so two clusters emitting the same call spelling get distinct occurrences.
+    let mut call_spelling_occurrences: HashMap<(FileId, String), usize> = HashMap::new();
+
     // For each macro db entry, generate a new macro/func definition and add that to the top/bottom of the file
     // For each macro invocation, replace the invocation with a macro/func call
-    for (_loc_decl, cluster) in hayroll_macro_db.map.iter() {
+    for ((loc_ref_begin, _signature), cluster) in ordered_clusters {
         // Work in the declaration file for inserts
         let decl_file_id = cluster.file_id();
         let decl_root = syntax_roots.get(&decl_file_id).unwrap();
@@ -129,25 +299,125 @@ pub fn run(workspace_path: &Path) -> Result<()> {
 
         let anti_name_duplicate = *name_has_duplicates.get(&cluster.name()).unwrap_or(&false);
 
-        if cluster.can_be_fn() {
+        // Track where each invocation's region lived, so we can emit a provenance
+        // entry once the definition/replacement shape for this cluster is decided.
+        let mut invocation_ranges: Vec<(FileId, syntax::TextRange)> = Vec::new();
+
+        if let Some(shared_fn) = generic_fns.get(loc_ref_begin) {
+            // This cluster is one arm of a declaration site that several distinct-signature
+            // clusters were unified into a single generic `fn` for; only the first cluster visited
+            // here inserts the shared definition, but every cluster still rewrites its own
+            // invocations into calls against it (using its own, generic-fn-wide name, not its own
+            // per-signature `name_with_signature()`).
+            let fn_ = shared_fn.clone();
+            let fn_ = match crate_name_for_file(&sema_for_crate_names, decl_file_id) {
+                Some(crate_name) => rewrite_dollar_crate(fn_, &crate_name),
+                None => fn_,
+            };
+            let fn_range = fn_.syntax().text_range();
+            let fn_name = cluster.name();
+            if generic_fn_inserted.insert(loc_ref_begin.clone()) {
+                if emit_hayroll_module {
+                    qualify_and_expose_external_refs(
+                        fn_.syntax(),
+                        &decl_root,
+                        &mut editor,
+                        &hayroll_module_names,
+                    );
+                    hayroll_module_defs_by_file
+                        .entry(decl_file_id)
+                        .or_default()
+                        .push(format!("pub(crate) {}", fn_.syntax().text()));
+                } else if emit_dedicated_macros_module {
+                    dedicated_module_defs.push(format!("pub {}", fn_.syntax().text()));
+                    dedicated_module_anchor.get_or_insert(decl_file_id);
+                    dedicated_module_consumers.insert(decl_file_id);
+                } else {
+                    let fn_elem = fn_.syntax().syntax_element().clone();
+                    editor.insert_all(
+                        bot_pos(&decl_root),
+                        vec![get_empty_line_element_mut(), fn_elem],
+                    );
+                }
+            }
+
+            let arg_requires_lvalue = cluster.args_require_lvalue();
+            for inv in cluster.invocations.iter() {
+                if emit_dedicated_macros_module {
+                    dedicated_module_consumers.insert(inv.file_id());
+                }
+                let code_region = inv.seed.get_raw_code_region_with_index(true, src_loc_indices.get(&inv.file_id()));
+                let region_element_range = code_region.syntax_element_range();
+                invocation_ranges.push((inv.file_id(), region_covering_range(&region_element_range)));
+                let fn_call_node =
+                    inv.call_expr_or_stmt_mut_with_name(&fn_name, &arg_requires_lvalue);
+                if emit_hayroll_module {
+                    qualify_call_site_with_module(&fn_call_node, "hayroll");
+                }
+                let fn_call_elem = fn_call_node.syntax_element();
+                let expr_opt = match &code_region {
+                    CodeRegion::Expr(expr) => Some(expr.clone()),
+                    _ => None,
+                };
+                let replacement = maybe_wrap_else_branch(expr_opt, fn_call_elem.clone());
+                editor.replace_all(region_element_range, vec![replacement]);
+            }
+
+            builder_set.record_macro_provenance(MacroProvenanceEntry {
+                rust_name: cluster.name(),
+                loc_decl: loc_ref_begin.clone(),
+                loc_inv: cluster.invocations[0].loc_begin(),
+                definition_file: decl_file_id,
+                definition_range: fn_range,
+                invocation_ranges,
+            });
+        } else if cluster.can_be_fn(Some(&sema_for_crate_names)) {
             // Add the function definition to the bottom of the file
-            let fn_ = cluster.fn_(anti_name_duplicate);
-            let fn_elem = fn_.syntax().syntax_element().clone();
-            editor.insert_all(
-                bot_pos(&decl_root),
-                vec![get_empty_line_element_mut(), fn_elem],
-            );
+            let fn_ = cluster.fn_(Some(&sema_for_crate_names));
+            let fn_ = match crate_name_for_file(&sema_for_crate_names, decl_file_id) {
+                Some(crate_name) => rewrite_dollar_crate(fn_, &crate_name),
+                None => fn_,
+            };
+            let fn_range = fn_.syntax().text_range();
+            if emit_hayroll_module {
+                qualify_and_expose_external_refs(
+                    fn_.syntax(),
+                    &decl_root,
+                    &mut editor,
+                    &hayroll_module_names,
+                );
+                hayroll_module_defs_by_file
+                    .entry(decl_file_id)
+                    .or_default()
+                    .push(format!("pub(crate) {}", fn_.syntax().text()));
+            } else if emit_dedicated_macros_module {
+                dedicated_module_defs.push(format!("pub {}", fn_.syntax().text()));
+                dedicated_module_anchor.get_or_insert(decl_file_id);
+                dedicated_module_consumers.insert(decl_file_id);
+            } else {
+                let fn_elem = fn_.syntax().syntax_element().clone();
+                editor.insert_all(
+                    bot_pos(&decl_root),
+                    vec![get_empty_line_element_mut(), fn_elem],
+                );
+            }
 
             // Call convention, which args must stay lvalue (ptr convention)
             let arg_requires_lvalue = cluster.args_require_lvalue();
 
             // Replace the macro expansions with the function calls
             for inv in cluster.invocations.iter() {
-                let code_region = inv.seed.get_raw_code_region(true);
+                if emit_dedicated_macros_module {
+                    dedicated_module_consumers.insert(inv.file_id());
+                }
+                let code_region = inv.seed.get_raw_code_region_with_index(true, src_loc_indices.get(&inv.file_id()));
                 let region_element_range = code_region.syntax_element_range();
-                let fn_call_elem = inv
-                    .call_expr_or_stmt_mut(&arg_requires_lvalue, anti_name_duplicate)
-                    .syntax_element();
+                invocation_ranges.push((inv.file_id(), region_covering_range(&region_element_range)));
+                let fn_call_node = inv.call_expr_or_stmt_mut(&arg_requires_lvalue, anti_name_duplicate);
+                if emit_hayroll_module {
+                    qualify_call_site_with_module(&fn_call_node, "hayroll");
+                }
+                let fn_call_elem = fn_call_node.syntax_element();
                 let expr_opt = match &code_region {
                     CodeRegion::Expr(expr) => Some(expr.clone()),
                     _ => None,
@@ -155,33 +425,211 @@ pub fn run(workspace_path: &Path) -> Result<()> {
                 let replacement = maybe_wrap_else_branch(expr_opt, fn_call_elem.clone());
                 editor.replace_all(region_element_range, vec![replacement]);
             }
+
+            builder_set.record_macro_provenance(MacroProvenanceEntry {
+                rust_name: cluster.name(),
+                loc_decl: loc_ref_begin.clone(),
+                loc_inv: cluster.invocations[0].loc_begin(),
+                definition_file: decl_file_id,
+                definition_range: fn_range,
+                invocation_ranges,
+            });
+        } else if let Some(shared_macro_rules) = merged_macro_rules.get(loc_ref_begin) {
+            // This cluster is one arm of a declaration site that several structurally-compatible
+            // clusters were merged into a single `macro_rules!` for; only the first cluster visited
+            // here inserts the shared definition, but every cluster still rewrites its own
+            // invocations into calls against it (using the decl site's own shared `cluster.name()`,
+            // not any individual cluster's `name_with_signature()`).
+            let macro_rules = shared_macro_rules.clone();
+            let macro_rules_range = macro_rules.syntax().text_range();
+            let macro_name = cluster.name();
+            if merged_macro_rules_inserted.insert(loc_ref_begin.clone()) {
+                if emit_hayroll_module {
+                    qualify_and_expose_external_refs(
+                        macro_rules.syntax(),
+                        &decl_root,
+                        &mut editor,
+                        &hayroll_module_names,
+                    );
+                    hayroll_module_defs_by_file
+                        .entry(decl_file_id)
+                        .or_default()
+                        .push(format!("pub(crate) {}", macro_rules.syntax().text()));
+                } else if emit_dedicated_macros_module {
+                    dedicated_module_defs.push(format!("pub {}", macro_rules.syntax().text()));
+                    dedicated_module_anchor.get_or_insert(decl_file_id);
+                    dedicated_module_consumers.insert(decl_file_id);
+                } else {
+                    let macro_rules_elem = macro_rules.syntax().syntax_element();
+                    let top = top_pos(&decl_root);
+                    editor.insert_all(top, vec![macro_rules_elem, get_empty_line_element_mut()]);
+                }
+            }
+
+            for inv in cluster.invocations.iter() {
+                if emit_dedicated_macros_module {
+                    dedicated_module_consumers.insert(inv.file_id());
+                }
+                let code_region = inv.seed.get_raw_code_region_with_index(true, src_loc_indices.get(&inv.file_id()));
+                let macro_call = inv.macro_call_with_name(&macro_name);
+                if emit_hayroll_module {
+                    qualify_macro_call_with_module(&macro_call, "hayroll");
+                }
+                let call_spelling = macro_call.syntax().text().to_string();
+                let occurrence = call_spelling_occurrences
+                    .entry((decl_file_id, call_spelling.clone()))
+                    .or_insert(0);
+                round_trip_checks.push(RoundTripCheck {
+                    decl_file_id,
+                    rust_name: macro_name.clone(),
+                    loc_inv: inv.loc_begin(),
+                    call_spelling: call_spelling.clone(),
+                    occurrence: *occurrence,
+                    expected_text: code_region.peel_tag().to_string(),
+                });
+                *occurrence += 1;
+                let macro_call_node = macro_call.syntax().syntax_element();
+
+                match &code_region {
+                    CodeRegion::Expr(expr) => {
+                        let region_element_range = code_region.syntax_element_range();
+                        invocation_ranges.push((inv.file_id(), region_covering_range(&region_element_range)));
+                        let replacement =
+                            maybe_wrap_else_branch(Some(expr.clone()), macro_call_node.clone());
+                        editor.replace_all(region_element_range, vec![replacement]);
+                    }
+                    CodeRegion::Stmts { .. } => {
+                        let region_element_range = code_region.syntax_element_range();
+                        invocation_ranges.push((inv.file_id(), region_covering_range(&region_element_range)));
+                        editor.replace_all(region_element_range, vec![macro_call_node]);
+                    }
+                    CodeRegion::Decls(_) => {
+                        let mut items = code_region.syntax_element_vec();
+                        let seed_item = inv.seed.get_raw_decls_tag_item();
+                        items.push(seed_item.syntax().syntax_element().clone());
+                        invocation_ranges.push((inv.file_id(), seed_item.syntax().text_range()));
+
+                        let inv_root = syntax_roots.get(&inv.file_id()).unwrap();
+                        let bot = bot_pos(&inv_root);
+                        for item in items {
+                            editor.delete(item);
+                        }
+                        editor.insert_all(bot, vec![get_empty_line_element_mut(), macro_call_node]);
+                    }
+                }
+            }
+
+            builder_set.record_macro_provenance(MacroProvenanceEntry {
+                rust_name: macro_name,
+                loc_decl: loc_ref_begin.clone(),
+                loc_inv: cluster.invocations[0].loc_begin(),
+                definition_file: decl_file_id,
+                definition_range: macro_rules_range,
+                invocation_ranges,
+            });
         } else if cluster.invs_internally_structurally_compatible() {
-            // Not type-compatible, but can still be reconstructed as a Rust macro
-            let macro_rules = cluster.macro_rules(anti_name_duplicate);
-            let macro_rules_elem = macro_rules.syntax().syntax_element();
-            let top = top_pos(&decl_root);
-            editor.insert_all(top, vec![macro_rules_elem, get_empty_line_element_mut()]);
+            // Not type-compatible, but can still be reconstructed as a Rust macro. Unlike C's
+            // textual substitution, `macro_rules!` hygiene won't let the body see a caller-local
+            // binding it didn't receive as a parameter, nor let a binding the body introduces leak
+            // out to code after the invocation -- detect both before committing to the hygienic
+            // expansion, since a leaked binding can't be preserved and a captured local needs
+            // promoting to an explicit `$name:ident` metavariable to keep working.
+            if cluster.invocations.iter().any(hygiene_binding_leaks) {
+                warn!(loc = %cluster.invocations[0].loc_begin(), "Hayroll macro body binds a name that code after the invocation reads again; macro_rules! hygiene can't reproduce that leak, emitting the hygienic expansion anyway");
+            }
+            let captured_locals = hygiene_captured_locals(cluster, &sema_for_crate_names);
+            // Crate-internal item references get qualified to `$crate::...` directly in the body,
+            // so the macro stays callable once lifted to `top_pos` regardless of what's in scope at
+            // any particular invocation site -- unlike the `fn_` branch above, this generated item
+            // IS macro-expansion machinery, so `$crate` is always meaningful here and (unlike `fn_`)
+            // must not be run through `rewrite_dollar_crate`, which would blow it back open to a
+            // literal crate name and reintroduce the same invocation-scope fragility.
+            let crate_qualified_paths = hygiene_crate_qualified_paths(
+                cluster,
+                &sema_for_crate_names,
+                decl_file_id,
+                &captured_locals,
+            );
+            let macro_rules = if captured_locals.is_empty() && crate_qualified_paths.is_empty() {
+                cluster.macro_rules(anti_name_duplicate)
+            } else {
+                cluster.macro_rules_with_hygiene(&captured_locals, &crate_qualified_paths)
+            };
+            let macro_rules_range = macro_rules.syntax().text_range();
+            if emit_hayroll_module {
+                qualify_and_expose_external_refs(
+                    macro_rules.syntax(),
+                    &decl_root,
+                    &mut editor,
+                    &hayroll_module_names,
+                );
+                hayroll_module_defs_by_file
+                    .entry(decl_file_id)
+                    .or_default()
+                    .push(format!("pub(crate) {}", macro_rules.syntax().text()));
+            } else if emit_dedicated_macros_module {
+                dedicated_module_defs.push(format!("pub {}", macro_rules.syntax().text()));
+                dedicated_module_anchor.get_or_insert(decl_file_id);
+                dedicated_module_consumers.insert(decl_file_id);
+            } else {
+                let macro_rules_elem = macro_rules.syntax().syntax_element();
+                let top = top_pos(&decl_root);
+                editor.insert_all(top, vec![macro_rules_elem, get_empty_line_element_mut()]);
+            }
+
+            // Capture each invocation's original region text before any edits land, so every one
+            // (not just the first) can be diffed against the macro's real expansion once this
+            // pass is applied. Calls with identical spelling (e.g. no-arg macros, or repeated
+            // identical arguments) are disambiguated by how many equally-spelled checks were
+            // already queued for this declaration file.
 
             // Replace the macro invocations with the macro calls
             for inv in cluster.invocations.iter() {
-                let code_region = inv.seed.get_raw_code_region(true);
-                let macro_call_node = inv.macro_call(anti_name_duplicate).syntax().syntax_element();
+                if emit_dedicated_macros_module {
+                    dedicated_module_consumers.insert(inv.file_id());
+                }
+                let code_region = inv.seed.get_raw_code_region_with_index(true, src_loc_indices.get(&inv.file_id()));
+                let macro_call = if captured_locals.is_empty() {
+                    inv.macro_call(anti_name_duplicate)
+                } else {
+                    inv.macro_call_with_captures(&captured_locals)
+                };
+                if emit_hayroll_module {
+                    qualify_macro_call_with_module(&macro_call, "hayroll");
+                }
+                let call_spelling = macro_call.syntax().text().to_string();
+                let occurrence = call_spelling_occurrences
+                    .entry((decl_file_id, call_spelling.clone()))
+                    .or_insert(0);
+                round_trip_checks.push(RoundTripCheck {
+                    decl_file_id,
+                    rust_name: cluster.name(),
+                    loc_inv: inv.loc_begin(),
+                    call_spelling: call_spelling.clone(),
+                    occurrence: *occurrence,
+                    expected_text: code_region.peel_tag().to_string(),
+                });
+                *occurrence += 1;
+                let macro_call_node = macro_call.syntax().syntax_element();
 
                 match &code_region {
                     CodeRegion::Expr(expr) => {
                         let region_element_range = code_region.syntax_element_range();
+                        invocation_ranges.push((inv.file_id(), region_covering_range(&region_element_range)));
                         let replacement =
                             maybe_wrap_else_branch(Some(expr.clone()), macro_call_node.clone());
                         editor.replace_all(region_element_range, vec![replacement]);
                     }
                     CodeRegion::Stmts { .. } => {
                         let region_element_range = code_region.syntax_element_range();
+                        invocation_ranges.push((inv.file_id(), region_covering_range(&region_element_range)));
                         editor.replace_all(region_element_range, vec![macro_call_node]);
                     }
                     CodeRegion::Decls(_) => {
                         let mut items = code_region.syntax_element_vec();
                         let seed_item = inv.seed.get_raw_decls_tag_item();
                         items.push(seed_item.syntax().syntax_element().clone());
+                        invocation_ranges.push((inv.file_id(), seed_item.syntax().text_range()));
 
                         // Remove items then insert macro call at bottom of the file of invocation
                         let inv_root = syntax_roots.get(&inv.file_id()).unwrap();
@@ -193,22 +641,125 @@ pub fn run(workspace_path: &Path) -> Result<()> {
                     }
                 }
             }
+
+            builder_set.record_macro_provenance(MacroProvenanceEntry {
+                rust_name: cluster.name(),
+                loc_decl: loc_ref_begin.clone(),
+                loc_inv: cluster.invocations[0].loc_begin(),
+                definition_file: decl_file_id,
+                definition_range: macro_rules_range,
+                invocation_ranges,
+            });
         } else {
             warn!(loc = %cluster.invocations[0].loc_begin(), "Hayroll macro cannot be converted: incompatible argument usage; skipping");
         }
         builder_set.add_file_edits(decl_file_id, editor);
     }
 
+    // If hayroll-module mode collected any definitions, fold each file's own into one inline
+    // `mod hayroll { ... }` block at the bottom of that file, tidier than scattering generated
+    // items at file scope (and avoiding name collisions `name_has_duplicates` would otherwise
+    // leave visible at that scope).
+    if emit_hayroll_module {
+        for (file_id, defs) in &hayroll_module_defs_by_file {
+            let root = syntax_roots.get(file_id).unwrap();
+            let mod_text = format!("mod hayroll {{\n{}\n}}", defs.join("\n\n"));
+            // Each `defs` entry was already prettified (and thus indented) for life as a
+            // standalone top-level item; re-prettifying the assembled block recomputes every
+            // descendant's whitespace from the `mod`'s own brace nesting, the same way
+            // rust-analyzer reindents a macro expansion, so the folded definitions read as if
+            // they'd been written inside the module directly instead of pasted in verbatim.
+            let mod_item: Item = prettify_generated_node(ast_from_text(&mod_text));
+            let mut editor = builder_set.make_editor(root.syntax());
+            editor.insert_all(
+                bot_pos(root),
+                vec![get_empty_line_element_mut(), mod_item.syntax().syntax_element()],
+            );
+            builder_set.add_file_edits(*file_id, editor);
+        }
+    }
+
+    // If dedicated-module mode collected any definitions, emit them all into one new
+    // `hayroll_macros.rs` next to the crate root, `mod`-declare it there, and `use`-import it
+    // into every file that used to carry an inline definition or call one of its macros/fns.
+    if emit_dedicated_macros_module && !dedicated_module_defs.is_empty() {
+        let anchor_file = dedicated_module_anchor.expect("defs collected without an anchor file");
+        let crate_root_file = crate_root_file_for_file(&sema_for_crate_names, anchor_file)
+            .unwrap_or(anchor_file);
+
+        builder_set.builder_mut(crate_root_file).create_file(
+            AnchoredPathBuf {
+                anchor: crate_root_file,
+                path: "hayroll_macros.rs".to_string(),
+            },
+            dedicated_module_defs.join("\n\n"),
+        );
+
+        let mut root_editor = builder_set.make_editor(
+            syntax_roots.get(&crate_root_file).unwrap().syntax(),
+        );
+        let mod_item: Item = ast_from_text("mod hayroll_macros;");
+        root_editor.insert_all(
+            top_pos(syntax_roots.get(&crate_root_file).unwrap()),
+            vec![mod_item.syntax().syntax_element(), get_empty_line_element_mut()],
+        );
+        builder_set.add_file_edits(crate_root_file, root_editor);
+
+        let crate_name = crate_name_for_file(&sema_for_crate_names, crate_root_file)
+            .unwrap_or_else(|| "crate".to_string());
+        for consumer_file in dedicated_module_consumers {
+            // The crate root itself already has `mod hayroll_macros;`, so it reaches the
+            // module as a direct child rather than through the crate's own extern-prelude name.
+            let use_path = if consumer_file == crate_root_file {
+                "hayroll_macros".to_string()
+            } else {
+                format!("{crate_name}::hayroll_macros")
+            };
+            let consumer_root = syntax_roots.get(&consumer_file).unwrap();
+            let mut consumer_editor = builder_set.make_editor(consumer_root.syntax());
+            let use_item: Item = ast_from_text(&format!("use {use_path}::*;"));
+            consumer_editor.insert_all(
+                top_pos(consumer_root),
+                vec![use_item.syntax().syntax_element(), get_empty_line_element_mut()],
+            );
+            builder_set.add_file_edits(consumer_file, consumer_editor);
+        }
+    }
+
     // Finalize edits from the single global builder
-    let source_change = builder_set.finish();
+    let (source_change, provenance) = builder_set.finish();
     // Apply edits to the in-memory DB via file_text inputs
     apply_source_change(&mut db, &source_change);
 
     // ---- Second Pass: handle conditional macros ----
 
     let syntax_roots: HashMap<FileId, SourceFile> = collect_syntax_roots_from_db(&db);
+
+    // Verify each generated macro actually reconstructs its original region now that the First
+    // Pass's edits have landed in `db`. A mismatch means the string-built `macro_rules!`/
+    // `macro_call` diverged from the source it was built from, which is the one thing
+    // `ast_from_text` construction can't guarantee on its own. This is a correctness gate, not a
+    // diagnostic: any mismatch aborts the run rather than silently writing out a rewrite that
+    // isn't semantics-preserving.
+    let sema = Semantics::new(&db);
+    let round_trip_failures: Vec<String> = round_trip_checks
+        .iter()
+        .filter_map(|check| verify_macro_round_trip(&sema, &syntax_roots, check).err())
+        .collect();
+    if !round_trip_failures.is_empty() {
+        for failure in &round_trip_failures {
+            tracing::error!("{failure}");
+        }
+        anyhow::bail!(
+            "{} Hayroll macro(s) failed round-trip verification",
+            round_trip_failures.len()
+        );
+    }
+
     let mut builder_set = SourceChangeBuilderSet::from_syntax_roots(&syntax_roots);
-    let hayroll_seeds: Vec<HayrollSeed> = extract_hayroll_seeds_from_syntax_roots(&syntax_roots);
+    let (hayroll_seeds, seed_diagnostics): (Vec<HayrollSeed>, Vec<HayrollDiagnostic>) =
+        extract_hayroll_seeds_from_syntax_roots_with_mode(&syntax_roots, extract_mode);
+    emit_hayroll_diagnostics(diagnostic_format, &vfs, &syntax_roots, &seed_diagnostics);
 
     // Print number of syntax roots found
     println!("Found {} Rust files in the workspace", syntax_roots.len());
@@ -226,7 +777,12 @@ pub fn run(workspace_path: &Path) -> Result<()> {
     let teds = hayroll_conditional_macros
         .iter()
         .flat_map(|conditional_macro| {
-            let new_teds = conditional_macro.attach_cfg_teds(&mut builder_set);
+            let new_teds = conditional_macro.attach_cfg_teds(
+                &mut builder_set,
+                Some(&sema),
+                compile_time_conditional_branches,
+                Some(premise_dict),
+            );
             new_teds
         })
         .collect::<Vec<Box<dyn FnOnce()>>>();
@@ -236,7 +792,7 @@ pub fn run(workspace_path: &Path) -> Result<()> {
     }
 
     // Finalize edits from the single global builder
-    let source_change = builder_set.finish();
+    let (source_change, _provenance) = builder_set.finish();
     // Apply edits to the in-memory DB via file_text inputs
     apply_source_change(&mut db, &source_change);
 
@@ -255,7 +811,7 @@ pub fn run(workspace_path: &Path) -> Result<()> {
 
     for item in items {
         let mut editor = builder_set.make_editor(item.syntax());
-        let file_id = builder_set.file_id_of_node(item.syntax()).unwrap();
+        let file_id = builder_set.try_file_id_of_node(item.syntax()).unwrap();
 
         // Remove Hayroll tag items (detected by embedded JSON with {"hayroll": true})
         if item_is_hayroll_tag(&item) {
@@ -283,7 +839,7 @@ pub fn run(workspace_path: &Path) -> Result<()> {
     }
 
     // Finalize edits from the single global builder
-    let source_change = builder_set.finish();
+    let (source_change, _provenance) = builder_set.finish();
     // Apply edits to the in-memory DB via file_text inputs
     apply_source_change(&mut db, &source_change);
 
@@ -300,6 +856,347 @@ pub fn run(workspace_path: &Path) -> Result<()> {
         fs::write(path, code)?;
     }
 
+    // Emit the macro provenance source map next to the workspace root, so downstream
+    // tooling (diagnostics, editor integrations) can map reconstructed Rust macros
+    // back to their originating C locDecl/locInv without re-running extraction.
+    let provenance_path = workspace_path.join("hayroll_provenance.json");
+    fs::write(
+        &provenance_path,
+        serde_json::to_string_pretty(&provenance.to_json(&vfs))?,
+    )?;
+    info!(path = %provenance_path.display(), entries = provenance.entries.len(), "Wrote macro provenance source map");
+
+    Ok(())
+}
+
+// Smallest TextRange covering every element in `range`; used as a best-effort invocation span
+// in the provenance source map (the range reflects the pre-edit tree, not the post-rewrite file).
+fn region_covering_range(range: &std::ops::RangeInclusive<SyntaxElement>) -> syntax::TextRange {
+    range.start().text_range().cover(range.end().text_range())
+}
+
+// The display name of the crate `file_id` belongs to, for stripping `$crate` out of generated
+// definitions; `None` if the file isn't part of a named crate (e.g. a virtual/synthetic root).
+fn crate_name_for_file(sema: &Semantics<'_, RootDatabase>, file_id: FileId) -> Option<String> {
+    sema.file_to_module_def(file_id)?
+        .krate()
+        .display_name(sema.db)
+        .map(|name| name.to_string())
+}
+
+// The crate root file that `file_id` belongs to, used to anchor a generated dedicated macros
+// module (both where it's created on disk and where its `mod` declaration is inserted).
+fn crate_root_file_for_file(sema: &Semantics<'_, RootDatabase>, file_id: FileId) -> Option<FileId> {
+    Some(sema.file_to_module_def(file_id)?.krate().root_file(sema.db))
+}
+
+// The top-level item in `root` named `name`, if any -- used by `qualify_and_expose_external_refs`
+// to tell a reference to something still at file scope apart from a local, a parameter, or a
+// std/prelude name, since this is brand-new synthesized code with no semantic binding to resolve
+// it through.
+fn top_level_item_named(root: &SourceFile, name: &str) -> Option<Item> {
+    root.items().find(|item| {
+        item.syntax()
+            .children()
+            .find_map(ast::Name::cast)
+            .is_some_and(|item_name| item_name.to_string() == name)
+    })
+}
+
+// Give `item` at least crate visibility if it currently has none, the same bar
+// `ensure_crate_visible` in `merger_core.rs` uses for a patch item moved across a file boundary --
+// a reference from inside the new `hayroll` module needs its target visible at least that far.
+// Already-`pub`/`pub(...)` items are left untouched.
+fn raise_visibility_if_needed(editor: &mut syntax::syntax_editor::SyntaxEditor, item: &Item) {
+    let has_vis = match item {
+        Item::Fn(it) => it.visibility().is_some(),
+        Item::Struct(it) => it.visibility().is_some(),
+        Item::Enum(it) => it.visibility().is_some(),
+        Item::Const(it) => it.visibility().is_some(),
+        Item::Static(it) => it.visibility().is_some(),
+        Item::TypeAlias(it) => it.visibility().is_some(),
+        Item::Trait(it) => it.visibility().is_some(),
+        _ => return,
+    };
+    if has_vis {
+        return;
+    }
+    let Some(first_token) = item.syntax().first_token() else {
+        return;
+    };
+    let vis: ast::Visibility = ast_from_text("pub(crate)");
+    editor.insert_all(
+        Position::before(&first_token),
+        vec![
+            vis.syntax().clone().syntax_element(),
+            syntax::NodeOrToken::Token(ast::make::tokens::whitespace(" ")),
+        ],
+    );
+}
+
+// Mirrors what rust-analyzer's `extract_module` assist does for a definition being moved into a
+// new module: walk every unqualified top-level path reference inside `generated_node` (a `fn` or
+// `macro_rules!` about to be folded into `mod hayroll { ... }`), and for each one that names a
+// top-level item still left behind in `decl_root` (as opposed to another definition that's also
+// moving into the module, a local, or a std/prelude name), rewrite the reference to `super::name`
+// and widen that item's visibility to `pub(crate)` if it doesn't have one already. `hayroll_names`
+// is the full set of names moving into the module, so a reference to a sibling that's moving
+// alongside `generated_node` is correctly left alone as purely internal to the module.
+fn qualify_and_expose_external_refs(
+    generated_node: &SyntaxNode,
+    decl_root: &SourceFile,
+    editor: &mut syntax::syntax_editor::SyntaxEditor,
+    hayroll_names: &HashSet<String>,
+) {
+    let top_level_paths: Vec<ast::Path> = generated_node
+        .descendants()
+        .filter_map(ast::Path::cast)
+        .filter(|path| path.qualifier().is_none())
+        .filter(|path| path.syntax().parent().and_then(ast::Path::cast).is_none())
+        .collect();
+
+    for path in top_level_paths {
+        let Some(name_ref) = path.segment().and_then(|segment| segment.name_ref()) else {
+            continue;
+        };
+        let name = name_ref.to_string();
+        if hayroll_names.contains(&name) {
+            // Stays internal to the module -- its own definition is moving in alongside it.
+            continue;
+        }
+        let Some(target_item) = top_level_item_named(decl_root, &name) else {
+            // Not a reference to a top-level item in this file at all (a local, a parameter, a
+            // std/prelude name, ...) -- nothing to qualify or expose.
+            continue;
+        };
+        let qualified: ast::Path = ast_from_text(&format!("super::{name}"));
+        ted::replace(path.syntax(), qualified.syntax());
+        raise_visibility_if_needed(editor, &target_item);
+    }
+}
+
+// Qualifies a generated call expression's (or expression-statement's) callee with
+// `{module_name}::`, for a call site emitted by `call_expr_or_stmt_mut` after its matching
+// definition has moved into that inline module. A leading `*` (the pointer-argument convention's
+// deref) is peeled first so the path underneath it is the one actually rewritten.
+fn qualify_call_site_with_module(call_node: &SyntaxNode, module_name: &str) {
+    let Some(mut expr) = ast::ExprStmt::cast(call_node.clone())
+        .and_then(|stmt| stmt.expr())
+        .or_else(|| ast::Expr::cast(call_node.clone()))
+    else {
+        return;
+    };
+    if let Some(inner) = ast::PrefixExpr::cast(expr.syntax().clone()).and_then(|prefix| prefix.expr()) {
+        expr = inner;
+    }
+    let Some(path) = ast::CallExpr::cast(expr.syntax().clone())
+        .and_then(|call| call.expr())
+        .and_then(|callee| ast::PathExpr::cast(callee.syntax().clone()))
+        .and_then(|path_expr| path_expr.path())
+    else {
+        return;
+    };
+    if path.qualifier().is_some() {
+        return;
+    }
+    let qualified: ast::Path = ast_from_text(&format!("{module_name}::{}", path.syntax().text()));
+    ted::replace(path.syntax(), qualified.syntax());
+}
+
+// Same idea as `qualify_call_site_with_module`, for the `macro_call` shape instead of a function
+// call expression.
+fn qualify_macro_call_with_module(macro_call: &ast::MacroCall, module_name: &str) {
+    let Some(path) = macro_call.path() else {
+        return;
+    };
+    if path.qualifier().is_some() {
+        return;
+    }
+    let qualified: ast::Path = ast_from_text(&format!("{module_name}::{}", path.syntax().text()));
+    ted::replace(path.syntax(), qualified.syntax());
+}
+
+// A binding the macro body introduces via `let` that's also referenced again *after* the
+// invocation's own statements in the same enclosing block -- the one shape `macro_rules!` hygiene
+// can never reproduce (the binding gets a fresh syntax context the code after the expansion can't
+// see), unlike a captured read, which can be threaded through as an explicit metavariable instead.
+fn hygiene_binding_leaks(inv: &HayrollMacroInv) -> bool {
+    let region = inv.seed.get_raw_code_region(true);
+    let CodeRegion::Stmts { parent, range } = &region else {
+        // An Expr or Decls region has no "after the invocation" in the same block to leak into.
+        return false;
+    };
+    let introduced = hygiene_bound_names(&region);
+    if introduced.is_empty() {
+        return false;
+    }
+    parent
+        .statements()
+        .enumerate()
+        .filter(|(i, _)| *i > *range.end())
+        .flat_map(|(_, stmt)| stmt.syntax().descendants().filter_map(ast::PathExpr::cast))
+        .filter_map(|path_expr| path_expr.path())
+        .filter(|path| path.qualifier().is_none())
+        .any(|path| {
+            path.segment()
+                .and_then(|segment| segment.name_ref())
+                .is_some_and(|name_ref| introduced.contains(&name_ref.to_string()))
+        })
+}
+
+// Free names in the cluster's first invocation that resolve, at some invocation's own call site,
+// to a local binding live in the caller's scope -- these are what actually need promoting to a
+// `$name:ident` metavariable. A free name that instead resolves to a `fn`, `const`, or other item
+// needs no special handling, since `macro_rules!` hygiene already lets item references cross the
+// expansion boundary unhindered; only local-variable hygiene is the part C's textual substitution
+// relied on callers not noticing.
+fn hygiene_captured_locals(
+    cluster: &HayrollMacroCluster,
+    sema: &Semantics<'_, RootDatabase>,
+) -> BTreeSet<String> {
+    let candidate_names: HashSet<String> =
+        cluster.invocations[0].capturing_idents(None).into_iter().collect();
+    if candidate_names.is_empty() {
+        return BTreeSet::new();
+    }
+
+    let mut captured = BTreeSet::new();
+    for inv in cluster.invocations.iter() {
+        for name in inv.capturing_idents(Some(sema)) {
+            if candidate_names.contains(&name) {
+                captured.insert(name);
+            }
+        }
+    }
+    captured
+}
+
+// The `$crate::...`-rooted path to `def`, if it's declared in the same crate `origin_file`
+// belongs to -- `None` for an item outside that crate (std/prelude, or another crate in the
+// workspace), which is left as a bare name in the generated macro body exactly as it is today.
+fn crate_qualified_path(
+    sema: &Semantics<'_, RootDatabase>,
+    origin_file: FileId,
+    def: hir::ModuleDef,
+) -> Option<String> {
+    let origin_krate = sema.file_to_module_def(origin_file)?.krate();
+    if def.module(sema.db)?.krate() != origin_krate {
+        return None;
+    }
+    let root_module = origin_krate.root_module(sema.db);
+    let mod_path = root_module.find_use_path(sema.db, hir::ItemInNs::from(def), false, true)?;
+    let spelled = mod_path.to_string();
+    let relative = spelled.strip_prefix("crate::").unwrap_or(&spelled);
+    Some(format!("$crate::{relative}"))
+}
+
+// Free names in the cluster's first invocation that resolve to an item (as opposed to a local
+// binding, already handled by `hygiene_captured_locals`) declared in the same crate as the macro's
+// own declaration file -- these are what need rewriting to a `$crate::...`-rooted path, the same
+// way a `#[macro_export]`-style macro always qualifies its own crate's items, so that once the
+// macro is lifted to `top_pos` it keeps resolving regardless of what's imported at any particular
+// invocation site. Already-captured names are skipped: a name that resolves to a local at any
+// invocation is promoted to a metavariable instead, never qualified, since the two rewrites are
+// mutually exclusive per name.
+fn hygiene_crate_qualified_paths(
+    cluster: &HayrollMacroCluster,
+    sema: &Semantics<'_, RootDatabase>,
+    decl_file_id: FileId,
+    already_captured: &BTreeSet<String>,
+) -> HashMap<String, String> {
+    let param_names = cluster.invocations[0].param_names();
+
+    let mut qualified = HashMap::new();
+    for inv in cluster.invocations.iter() {
+        let region = inv.seed.get_raw_code_region(true);
+        for path in hygiene_free_name_paths(&region, &param_names) {
+            let Some(name) = path
+                .segment()
+                .and_then(|segment| segment.name_ref())
+                .map(|name_ref| name_ref.to_string())
+            else {
+                continue;
+            };
+            if already_captured.contains(&name) || qualified.contains_key(&name) {
+                continue;
+            }
+            let Some(PathResolution::Def(def)) = sema.resolve_path(&path) else {
+                continue;
+            };
+            if let Some(qualified_path) = crate_qualified_path(sema, decl_file_id, def) {
+                qualified.insert(name, qualified_path);
+            }
+        }
+    }
+    qualified
+}
+
+// A single round-trip assertion: expanding the macro call spelled `call_spelling` in
+// `decl_file_id` should reproduce `expected_text` (the original, untagged region it replaced).
+// `occurrence` disambiguates invocations that happen to share an identical call spelling (e.g.
+// no-arg macros, or repeated identical arguments): it's the 0-based index of this invocation
+// among all checks queued so far for the same (decl_file_id, call_spelling) pair.
+struct RoundTripCheck {
+    decl_file_id: FileId,
+    rust_name: String,
+    loc_inv: String,
+    call_spelling: String,
+    occurrence: usize,
+    expected_text: String,
+}
+
+// Ignore whitespace/trivia when comparing token streams, since the generated text's formatting
+// (indentation, line breaks) has no bearing on whether the macro reconstructs the same tokens.
+fn strip_trivia(text: &str) -> String {
+    text.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+// Re-find the macro call `check` refers to in the post-edit tree (stale node references from the
+// pre-edit tree can't be reused once `apply_source_change` re-parses the file), expand it via
+// `Semantics`, and compare the expansion against the region it replaced. Returns `Err` with a
+// human-readable description of the mismatch on failure, so callers can treat it as a hard
+// transformation error instead of a log line to skim past.
+fn verify_macro_round_trip(
+    sema: &Semantics<'_, RootDatabase>,
+    syntax_roots: &HashMap<FileId, SourceFile>,
+    check: &RoundTripCheck,
+) -> Result<(), String> {
+    let Some(root) = syntax_roots.get(&check.decl_file_id) else {
+        return Err(format!(
+            "{} ({}): declaration file missing after edits",
+            check.rust_name, check.loc_inv
+        ));
+    };
+
+    let Some(macro_call) = root
+        .syntax()
+        .descendants()
+        .filter_map(MacroCall::cast)
+        .filter(|mc| mc.syntax().text().to_string() == check.call_spelling)
+        .nth(check.occurrence)
+    else {
+        return Err(format!(
+            "{} ({}): generated macro call not found after edits",
+            check.rust_name, check.loc_inv
+        ));
+    };
+
+    let Some(macro_def) = sema.to_def(&macro_call) else {
+        return Err(format!(
+            "{} ({}): could not resolve generated macro call",
+            check.rust_name, check.loc_inv
+        ));
+    };
+    let expanded = sema.parse_or_expand(macro_def.as_file());
+
+    let expected = strip_trivia(&check.expected_text);
+    let actual = strip_trivia(&expanded.to_string());
+    if expected != actual {
+        return Err(format!(
+            "{} ({}): generated macro does not re-expand to the original region\n  expected: {}\n  actual: {}",
+            check.rust_name, check.loc_inv, check.expected_text, expanded
+        ));
+    }
     Ok(())
 }
 
@@ -1,6 +1,8 @@
 use std::collections::{HashMap, HashSet};
+use std::fs;
 
-use ide_db::{base_db::{SourceDatabase, SourceRootDatabase}, source_change::SourceChangeBuilder, EditionedFileId};
+use ide_db::{base_db::{SourceDatabase, SourceDatabaseFileInputExt, SourceRootDatabase}, source_change::SourceChangeBuilder, EditionedFileId};
+use serde_json;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
 use ide::{Edition, RootDatabase};
@@ -70,6 +72,76 @@ pub fn get_dollar_token_mut() -> SyntaxToken {
     dollar_token_mut
 }
 
+// Reformat a hand-built node (one we constructed by `format!`-ing a string template and parsing
+// it back with `ast_from_text`, not one carried over from real source) the way rust-analyzer
+// spaces macro expansions, so generated `macro_rules!`/`fn`/macro-call output reads like ordinary
+// source instead of whatever indentation the template happened to hardcode.
+pub fn prettify_generated_node<N: AstNode>(node: N) -> N {
+    let prettified = ide_db::syntax_helpers::insert_whitespace_into_node::insert_ws_into(node.syntax().clone());
+    N::cast(prettified).expect("prettify_generated_node: re-casting to the same AST kind must succeed")
+}
+
+// Rewrite `$crate::` qualifiers left in generated text (picked up when a code region's text was
+// itself sourced from a macro expansion) to the concrete crate name, since `$crate` only resolves
+// inside macro-expansion machinery and would otherwise leave the printed macro uncompilable
+// outside of it.
+pub fn rewrite_dollar_crate_text(text: &str, crate_name: &str) -> String {
+    text.replace("$crate::", &format!("{crate_name}::"))
+}
+
+// Apply `rewrite_dollar_crate_text` to a whole node, round-tripping through text since `$crate`
+// only ever shows up as opaque token-tree text in these hand-built nodes (never a structured
+// `ast::Path`), so there's nothing to gain from a syntax-tree-level rewrite here.
+pub fn rewrite_dollar_crate<N: AstNode>(node: N, crate_name: &str) -> N {
+    let text = node.syntax().text().to_string();
+    if !text.contains("$crate::") {
+        return node;
+    }
+    ast_from_text::<N>(&rewrite_dollar_crate_text(&text, crate_name)).clone_for_update()
+}
+
+// Get mutable tokens spelling `$($name),*`, the repetition group substituted in a generated
+// macro_rules! body for a variadic (__VA_ARGS__-style) argument. The macro call parser accepts
+// `$` unconditionally inside a token tree, so a throwaway call is enough to mint real tokens.
+pub fn get_variadic_repetition_tokens_mut(name: &str) -> Vec<SyntaxElement> {
+    let macro_call = ast_from_text::<ast::MacroCall>(&format!("M!($(${name}),*)"));
+    let macro_call = macro_call.clone_for_update();
+    let token_tree = macro_call.token_tree().unwrap();
+    token_tree
+        .syntax()
+        .children_with_tokens()
+        .filter(|element| !matches!(element.kind(), T!['('] | T![')']))
+        .collect()
+}
+
+// Get a mutable token tree spelling a `${count(name)}` metavariable expression, for a variadic
+// argument whose tag marks it as consumed through a C argument-counting idiom (`ArgUsage::Count`)
+// rather than spelled out value by value.
+pub fn get_count_meta_expr_tokens_mut(name: &str) -> Vec<SyntaxElement> {
+    let macro_call = ast_from_text::<ast::MacroCall>(&format!("M!(${{count({name})}})"));
+    let macro_call = macro_call.clone_for_update();
+    let token_tree = macro_call.token_tree().unwrap();
+    token_tree
+        .syntax()
+        .children_with_tokens()
+        .filter(|element| !matches!(element.kind(), T!['('] | T![')']))
+        .collect()
+}
+
+// Get a mutable token tree spelling a `${ignore(name)}` metavariable expression, for a variadic
+// argument whose tag marks it as consumed only for its repetition count/side effects
+// (`ArgUsage::Ignore`), never spelled out.
+pub fn get_ignore_meta_expr_tokens_mut(name: &str) -> Vec<SyntaxElement> {
+    let macro_call = ast_from_text::<ast::MacroCall>(&format!("M!(${{ignore({name})}})"));
+    let macro_call = macro_call.clone_for_update();
+    let token_tree = macro_call.token_tree().unwrap();
+    token_tree
+        .syntax()
+        .children_with_tokens()
+        .filter(|element| !matches!(element.kind(), T!['('] | T![')']))
+        .collect()
+}
+
 pub fn get_empty_line_element_mut() -> SyntaxElement {
     let empty_line = ast::make::tokens::whitespace("\n");
     syntax::NodeOrToken::Token(empty_line)
@@ -109,6 +181,79 @@ where
     parent_until_kind_and_cond(node, |_| true)
 }
 
+// Macro-aware counterpart of `parent_until_kind_and_cond`: if `node`'s syntactic ancestors never
+// reach a `T` satisfying `condition` (e.g. because `node` sits inside a macro call's opaque token
+// tree, having been nested inside another cluster's already-reconstructed macro by an earlier
+// pass), fall back to climbing through the macro's expansion via
+// `Semantics::token_ancestors_with_macros`, which walks into the parsed expansion tree. `sema` is
+// `None` wherever no loaded workspace is available, in which case this degrades to the plain
+// syntactic walk. Tags in real (non-expanded) source never reach the fallback.
+pub fn parent_until_kind_and_cond_macro_aware<T>(
+    sema: Option<&hir::Semantics<'_, RootDatabase>>,
+    node: &impl ast::AstNode,
+    condition: fn(&T) -> bool,
+) -> Option<T>
+where
+    T: ast::AstNode,
+{
+    if let Some(found) = parent_until_kind_and_cond(node, condition) {
+        return Some(found);
+    }
+    let sema = sema?;
+    let token = node.syntax().first_token()?;
+    let found_in_expansion = sema
+        .token_ancestors_with_macros(token)
+        .filter_map(T::cast)
+        .find(condition)?;
+    // `found_in_expansion` was found by walking into a macro's expansion tree, so it isn't
+    // editable in place; resolve the corresponding node in the real file before handing it back.
+    real_node_via_original_range(sema, &found_in_expansion)
+}
+
+// Macro-aware counterpart of `parent_until_kind`. See `parent_until_kind_and_cond_macro_aware`.
+pub fn parent_until_kind_macro_aware<T>(
+    sema: Option<&hir::Semantics<'_, RootDatabase>>,
+    node: &impl ast::AstNode,
+) -> Option<T>
+where
+    T: ast::AstNode,
+{
+    parent_until_kind_and_cond_macro_aware(sema, node, |_| true)
+}
+
+// A node found by climbing through a macro's expansion (the `sema.token_ancestors_with_macros`
+// fallback in `parent_until_kind_and_cond_macro_aware`) lives in that expansion's synthetic
+// syntax tree, not in any real source file -- a `SourceChangeBuilderSet` built from real
+// `syntax_roots` has no `FileId` for it and can't resolve it for editing. Map the node back to
+// its real-file location via `sema.original_range`, then re-find the node of the same kind at
+// that range in the real file's own tree, which a `SourceChangeBuilderSet` can resolve normally.
+// A no-op (returns the same node) for a node that already lives in real source.
+pub fn real_node_via_original_range<T: ast::AstNode>(
+    sema: &hir::Semantics<'_, RootDatabase>,
+    node: &T,
+) -> Option<T> {
+    let file_range = sema.original_range(node.syntax());
+    let real_root = sema
+        .db
+        .parse(EditionedFileId::current_edition(file_range.file_id))
+        .tree();
+    node_at_range(real_root.syntax(), file_range.range)
+}
+
+// The node of kind `T` covering `range` in `root`'s tree, or the nearest ancestor covering it
+// when `range` lands inside a token rather than exactly on a node boundary. Used to relocate a
+// node found in one syntax tree to its structural counterpart in another tree that shares the
+// same text (e.g. a real file and a `clone_for_update` of it), where object identity can't be
+// used to find the corresponding node directly.
+pub fn node_at_range<T: ast::AstNode>(root: &SyntaxNode, range: syntax::TextRange) -> Option<T> {
+    let covering = root.covering_element(range);
+    let start = match covering {
+        syntax::NodeOrToken::Node(n) => n,
+        syntax::NodeOrToken::Token(t) => t.parent()?,
+    };
+    start.ancestors().find_map(T::cast)
+}
+
 // Takes a node and returns the parent node until the parent node satisfies the condition
 #[allow(dead_code)]
 pub fn parent_until(node: SyntaxNode, condition: fn(SyntaxNode) -> bool) -> Option<SyntaxNode> {
@@ -124,7 +269,9 @@ pub fn get_source_file(node: &impl ast::AstNode) -> ast::SourceFile {
     parent_until_kind::<ast::SourceFile>(node).unwrap()
 }
 
-#[derive(Clone, PartialEq, Eq)]
+// Lexicographic (line, then col) ordering matches the containment semantics `is_within` already
+// used, and is what makes the entries in `SrcLocIndex` binary-searchable.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct LnCol {
     pub line: u32,
     pub col: u32,
@@ -157,7 +304,22 @@ impl LnCol {
     }
 }
 
+// Parses an item's `#[c2rust::src_loc = "l:c"]` attribute, if it has one, into an `LnCol`. Items
+// without the attribute aren't decl region boundaries and yield `None`.
+fn item_src_loc(item: &ast::Item) -> Option<LnCol> {
+    item.attrs().find_map(|attr| {
+        let meta = attr.meta()?;
+        if meta.path()?.to_string() != "c2rust::src_loc" {
+            return None;
+        }
+        let string = ast::String::cast(meta.expr()?.syntax().first_token()?)?;
+        Some(LnCol::from_cu_ln_col(string.value().ok()?.as_ref()))
+    })
+}
+
 // Find all ast::Item in a SourceFile, who has a #[c2rust::src_loc = "l:c"] attribute within a range
+// O(items) full rescan of the SourceFile; kept as the fallback for callers that haven't built a
+// `SrcLocIndex` for it (see `SrcLocIndex::items_in_range` for the sub-linear counterpart).
 pub fn find_items_in_range(
     source_file: &ast::SourceFile,
     range: std::ops::RangeInclusive<LnCol>,
@@ -166,24 +328,43 @@ pub fn find_items_in_range(
         .syntax()
         .descendants()
         .filter_map(ast::Item::cast)
-        .filter(|item| {
-            item.attrs().any(|attr| {
-                attr.meta().map_or(false, |meta| {
-                    meta.path().map_or(false, |path| path.to_string() == "c2rust::src_loc")
-                        && meta.expr().map_or(false, |expr| {
-                            ast::String::cast(expr.syntax().first_token().unwrap())
-                                .map_or(false, |string| {
-                                    let cu_loc = string.value();
-                                    let loc = LnCol::from_cu_ln_col(&cu_loc.as_ref().unwrap());
-                                    loc.is_within(&range)
-                                })
-                        })
-                })
-            })
-        })
+        .filter(|item| item_src_loc(item).map_or(false, |loc| loc.is_within(&range)))
         .collect()
 }
 
+// Reusable index over a SourceFile's `#[c2rust::src_loc = "l:c"]`-tagged top-level items, built
+// once and queried with two binary searches per lookup instead of the O(items) rescan
+// `find_items_in_range` does on every call -- `HayrollSeed::Decls::get_raw_code_region` used to
+// pay that rescan cost once per decls seed, which is O(items * seeds) over a whole crate. Items
+// without a `src_loc` attribute are left out of the index, matching `find_items_in_range`'s own
+// filter (such an item never satisfies its predicate either).
+pub struct SrcLocIndex {
+    entries: Vec<(LnCol, ast::Item)>,
+}
+
+impl SrcLocIndex {
+    pub fn build(source_file: &ast::SourceFile) -> SrcLocIndex {
+        let mut entries: Vec<(LnCol, ast::Item)> = source_file
+            .syntax()
+            .descendants()
+            .filter_map(ast::Item::cast)
+            .filter_map(|item| item_src_loc(&item).map(|loc| (loc, item)))
+            .collect();
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        SrcLocIndex { entries }
+    }
+
+    // Two binary searches over the sorted index in place of `find_items_in_range`'s full rescan.
+    pub fn items_in_range(&self, range: &std::ops::RangeInclusive<LnCol>) -> Vec<ast::Item> {
+        let start = self.entries.partition_point(|(loc, _)| loc < range.start());
+        let end = self.entries.partition_point(|(loc, _)| loc <= range.end());
+        self.entries[start..end]
+            .iter()
+            .map(|(_, item)| item.clone())
+            .collect()
+    }
+}
+
 // Collect all parsed `SourceFile` roots from the database without using VFS.
 //
 // Strategy:
@@ -213,6 +394,46 @@ pub fn collect_syntax_roots_from_db(db: &RootDatabase) -> HashMap<FileId, Source
     out
 }
 
+// Apply a finished `SourceChange` to the in-memory `RootDatabase`, including any `FileSystemEdit`s
+// it carries (e.g. a `CreateFile` for a freshly generated module). Filesystem edits write straight
+// to disk via `fs::write` rather than through `db`/`Vfs`: a brand-new file has no pre-existing
+// `FileId`, and every caller's final write-back pass only revisits the file ids it collected
+// before editing, so it would never pick up a file created mid-run anyway.
+pub fn apply_source_change(db: &mut RootDatabase, source_change: &ide::SourceChange) {
+    // Best-effort transactional behavior: cancel outstanding queries first.
+    db.request_cancellation();
+
+    for file_system_edit in source_change.file_system_edits.iter() {
+        let (dst, contents) = match file_system_edit {
+            ide::FileSystemEdit::CreateFile { dst, initial_contents } => {
+                (dst, initial_contents.clone())
+            }
+            ide::FileSystemEdit::MoveFile { src, dst } => (dst, db.file_text(*src).to_string()),
+            ide::FileSystemEdit::MoveDir { src, src_id, dst } => {
+                // Dir moves aren't produced by any assist this tool drives; keep the same
+                // placeholder rust-analyzer itself falls back to for this case.
+                (dst, format!("{src_id:?}\n{src:?}"))
+            }
+        };
+        let source_root_id = db.file_source_root(dst.anchor);
+        let source_root = db.source_root(source_root_id);
+        let mut base = source_root.path_for_file(&dst.anchor).unwrap().clone();
+        base.pop();
+        let created_file_path = base.join(&dst.path).unwrap();
+        fs::write(created_file_path.as_path().unwrap(), contents)
+            .expect("apply_source_change: failed to write generated file to disk");
+    }
+
+    // Apply per-file text edits directly to DB inputs.
+    for (file_id, (text_edit, snippet)) in source_change.source_file_edits.iter() {
+        let mut code = db.file_text(*file_id).to_string();
+        text_edit.apply(&mut code);
+        if let Some(snippet) = snippet {
+            snippet.apply(&mut code);
+        }
+        db.set_file_text(*file_id, &code);
+    }
+}
 
 pub fn stmt_is_hayroll_tag(stmt: &ast::Stmt) -> bool {
     // Strategy: look for any byte string literal inside the stmt whose decoded contents
@@ -238,27 +459,101 @@ pub fn stmt_is_hayroll_tag(stmt: &ast::Stmt) -> bool {
     false
 }
 
+// Where a single reconstructed macro came from and what it produced, so downstream tooling (or a
+// user debugging a bad reconstruction) can map a synthesized `macro_rules!`/`fn` back to the exact
+// C header and call site that produced it.
+#[derive(Clone, Debug)]
+pub struct MacroProvenanceEntry {
+    pub rust_name: String,
+    pub loc_decl: String,
+    pub loc_inv: String,
+    pub definition_file: FileId,
+    pub definition_range: syntax::TextRange,
+    pub invocation_ranges: Vec<(FileId, syntax::TextRange)>,
+}
+
+impl MacroProvenanceEntry {
+    pub fn to_json(&self, vfs: &vfs::Vfs) -> serde_json::Value {
+        let range_json = |file_id: FileId, range: syntax::TextRange| {
+            serde_json::json!({
+                "file": vfs.file_path(file_id).to_string(),
+                "start": u32::from(range.start()),
+                "end": u32::from(range.end()),
+            })
+        };
+        serde_json::json!({
+            "rustName": self.rust_name,
+            "locDecl": self.loc_decl,
+            "locInv": self.loc_inv,
+            "definition": range_json(self.definition_file, self.definition_range),
+            "invocations": self.invocation_ranges.iter()
+                .map(|(file_id, range)| range_json(*file_id, *range))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+// All the macro provenance entries recorded over the lifetime of a single reconstruction run.
+#[derive(Clone, Debug, Default)]
+pub struct ProvenanceMap {
+    pub entries: Vec<MacroProvenanceEntry>,
+}
+
+impl ProvenanceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, entry: MacroProvenanceEntry) {
+        self.entries.push(entry);
+    }
+
+    pub fn to_json(&self, vfs: &vfs::Vfs) -> serde_json::Value {
+        serde_json::Value::Array(self.entries.iter().map(|e| e.to_json(vfs)).collect())
+    }
+}
+
 // A helper structure to manage multiple SourceChangeBuilders keyed by FileId.
 // It provides a facade mirroring a subset of SourceChangeBuilder's API, routing
 // calls to the appropriate underlying builder based on the file being edited.
 pub struct SourceChangeBuilderSet {
     builders: HashMap<FileId, SourceChangeBuilder>,
     root_to_file: HashMap<syntax::SyntaxNode, FileId>,
+    // The current mutable working-tree root owned by each builder. `make_mut`/`make_syntax_mut`
+    // of rust-analyzer's `SourceChangeBuilder` clone the whole tree on first use and reuse that
+    // clone afterwards, so this stays a single entry per file; it's refreshed every time we route
+    // through `make_mut`/`make_syntax_mut` below, so it never goes stale mid-edit.
+    mut_root_to_file: HashMap<syntax::SyntaxNode, FileId>,
+    provenance: ProvenanceMap,
 }
 
 impl SourceChangeBuilderSet {
-    pub fn new() -> Self { Self { builders: HashMap::new(), root_to_file: HashMap::new() } }
+    pub fn new() -> Self {
+        Self {
+            builders: HashMap::new(),
+            root_to_file: HashMap::new(),
+            mut_root_to_file: HashMap::new(),
+            provenance: ProvenanceMap::new(),
+        }
+    }
+
+    // Accumulate a macro's provenance record; collected alongside the edits so it can be handed
+    // back to the caller from `finish` instead of being discarded after editing.
+    pub fn record_macro_provenance(&mut self, entry: MacroProvenanceEntry) {
+        self.provenance.record(entry);
+    }
 
     // Pre-populate a builder per file, each with a full mutable tree initialized.
     pub fn from_syntax_roots(syntax_roots: &HashMap<FileId, ast::SourceFile>) -> Self {
         let mut set = SourceChangeBuilderSet::new();
         for (file_id, source_file) in syntax_roots {
             let mut builder = SourceChangeBuilder::new(*file_id);
-            // Initialize mutable tree for that file (mirrors previous helper behavior)
-            builder.make_mut(source_file.clone());
             let root = source_file.syntax().clone();
-            // let ptr = syntax::SyntaxNodePtr::new(&root);
             set.root_to_file.insert(root, *file_id);
+            // Initialize the mutable tree for that file up front, and record its root so
+            // resolution still works once callers only hold nodes from that mutated tree.
+            let mut_root = builder.make_mut(source_file.clone()).syntax().clone();
+            set.mut_root_to_file.insert(mut_root, *file_id);
             set.builders.insert(*file_id, builder);
         }
         set
@@ -270,17 +565,38 @@ impl SourceChangeBuilderSet {
 
     pub fn builder_mut(&mut self, file_id: FileId) -> &mut SourceChangeBuilder { self.get(file_id) }
 
-    // Attempt to derive the file id from an arbitrary node by walking to its immutable root.
-    // NOTE: This works only for nodes from the original syntax trees (immutable roots). After
-    // a node is cloned_for_update() the root changes and resolution may fail.
-    fn file_id_of_node(&self, node: &syntax::SyntaxNode) -> Option<FileId> {
+    // Attempt to derive the file id from an arbitrary node: first against the node's immutable
+    // root (covers nodes straight out of `from_syntax_roots`), then against its mutable working
+    // root (covers nodes already returned from `make_mut`/`make_syntax_mut`/`clone_for_update`).
+    // If neither is recognized -- e.g. the node's subtree was detached and `clone_for_update`'d
+    // again outside our bookkeeping -- falls back to relocating it by (kind, text range) against
+    // every known mutable root. That fallback is linear and best-effort (two files could in
+    // principle share a coincidental kind+range), but the common paths above stay O(1).
+    pub fn try_file_id_of_node(&self, node: &syntax::SyntaxNode) -> Option<FileId> {
         let root = node.ancestors().last().unwrap_or_else(|| node.clone());
-        self.root_to_file.get(&root).copied()
+        if let Some(file_id) = self.root_to_file.get(&root) {
+            return Some(*file_id);
+        }
+        if let Some(file_id) = self.mut_root_to_file.get(&root) {
+            return Some(*file_id);
+        }
+        self.mut_root_to_file.iter().find_map(|(mut_root, file_id)| {
+            mut_root
+                .descendants()
+                .any(|candidate| {
+                    candidate.kind() == node.kind() && candidate.text_range() == node.text_range()
+                })
+                .then_some(*file_id)
+        })
+    }
+
+    fn file_id_of_node(&self, node: &syntax::SyntaxNode) -> FileId {
+        self.try_file_id_of_node(node)
+            .expect("Unable to resolve FileId from node (did you forget from_syntax_roots?)")
     }
 
-    #[allow(dead_code)]
     pub fn builder_mut_for_node(&mut self, node: &syntax::SyntaxNode) -> &mut SourceChangeBuilder {
-        let file_id = self.file_id_of_node(node).expect("Unable to resolve FileId from node root");
+        let file_id = self.file_id_of_node(node);
         self.get(file_id)
     }
 
@@ -310,23 +626,32 @@ impl SourceChangeBuilderSet {
 
     // Convenience: infer file id from the node itself.
     pub fn make_mut<N: syntax::AstNode>(&mut self, node: N) -> N {
-        let file_id = self.file_id_of_node(node.syntax()).expect("Unable to resolve FileId from node");
-        self.get(file_id).make_mut(node)
+        let file_id = self.file_id_of_node(node.syntax());
+        let result = self.get(file_id).make_mut(node);
+        let new_root = result.syntax().ancestors().last().unwrap_or_else(|| result.syntax().clone());
+        self.mut_root_to_file.insert(new_root, file_id);
+        result
     }
 
     // Route make_syntax_mut to the appropriate builder.
     #[allow(dead_code)]
     pub fn make_syntax_mut(&mut self, node: syntax::SyntaxNode) -> syntax::SyntaxNode {
-        let file_id = self.file_id_of_node(&node).expect("Unable to resolve FileId from node");
-        self.get(file_id).make_syntax_mut(node)
+        let file_id = self.file_id_of_node(&node);
+        let result = self.get(file_id).make_syntax_mut(node);
+        let new_root = result.ancestors().last().unwrap_or_else(|| result.clone());
+        self.mut_root_to_file.insert(new_root, file_id);
+        result
     }
 
-    // Finish all builders, merging their SourceChanges.
-    pub fn finish(mut self) -> ide::SourceChange {
+    // Finish all builders, merging their SourceChanges, and hand back whatever macro provenance
+    // was recorded along the way.
+    pub fn finish(mut self) -> (ide::SourceChange, ProvenanceMap) {
         self.commit();
-        self.builders.into_iter().fold(ide::SourceChange::default(), |acc, (_fid, builder)| {
+        let provenance = std::mem::take(&mut self.provenance);
+        let source_change = self.builders.into_iter().fold(ide::SourceChange::default(), |acc, (_fid, builder)| {
             let change = builder.finish();
             acc.merge(change)
-        })
+        });
+        (source_change, provenance)
     }
 }
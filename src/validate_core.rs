@@ -0,0 +1,169 @@
+// Post-transform validation: after a pass has written its rewritten files to disk, shell out to
+// a configurable checker and report any compiler errors the rewrite introduced, optionally
+// restoring the pre-transform file contents when it finds some. Shared by `merger_core::run` and
+// `inliner_core::run`, the two passes that write straight to disk with no verification of their
+// own that the result still compiles.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use tracing::{error, warn};
+
+// How to check a workspace after a transform has written its rewritten files to disk.
+#[derive(Clone, Debug)]
+pub enum CheckerConfig {
+    // `cargo check --message-format=json [extra_args...]`, run with `extra_env` set.
+    CargoCommand {
+        extra_args: Vec<String>,
+        extra_env: HashMap<String, String>,
+    },
+    // An arbitrary checker command, for toolchains where plain `cargo check` isn't the right
+    // thing (a wrapper script, a cross-compiling checker, etc). Expected to emit the same
+    // `--message-format=json` diagnostic lines on stdout that `cargo check` does.
+    CustomCommand {
+        command: String,
+        args: Vec<String>,
+        extra_env: HashMap<String, String>,
+    },
+}
+
+impl Default for CheckerConfig {
+    fn default() -> Self {
+        CheckerConfig::CargoCommand {
+            extra_args: Vec::new(),
+            extra_env: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ValidationDiagnostic {
+    pub level: String,
+    pub message: String,
+}
+
+#[derive(Debug, Default)]
+pub struct ValidationReport {
+    pub errors: Vec<ValidationDiagnostic>,
+    pub warnings: Vec<ValidationDiagnostic>,
+}
+
+impl ValidationReport {
+    pub fn has_errors(&self) -> bool {
+        !self.errors.is_empty()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CargoCheckMessage {
+    reason: String,
+    #[serde(default)]
+    message: Option<CargoDiagnosticMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CargoDiagnosticMessage {
+    level: String,
+    message: String,
+    #[serde(default)]
+    rendered: Option<String>,
+}
+
+// Run the configured checker against `workspace_path` and parse its `--message-format=json`
+// (or equivalent) output into a `ValidationReport`. Diagnostics at levels other than
+// "error"/"warning" (e.g. "note", "help") are dropped; `cargo check`'s own non-compiler-message
+// lines (build script output, artifact notifications) are skipped via the `reason` field.
+pub fn run_checker(workspace_path: &Path, config: &CheckerConfig) -> Result<ValidationReport> {
+    let mut command = match config {
+        CheckerConfig::CargoCommand { extra_args, .. } => {
+            let mut command = Command::new("cargo");
+            command.arg("check").arg("--message-format=json");
+            command.args(extra_args);
+            command
+        }
+        CheckerConfig::CustomCommand { command, args, .. } => {
+            let mut command = Command::new(command);
+            command.args(args);
+            command
+        }
+    };
+    let extra_env = match config {
+        CheckerConfig::CargoCommand { extra_env, .. } => extra_env,
+        CheckerConfig::CustomCommand { extra_env, .. } => extra_env,
+    };
+    command.current_dir(workspace_path);
+    for (key, value) in extra_env {
+        command.env(key, value);
+    }
+
+    let output = command
+        .output()
+        .with_context(|| format!("failed to run checker in {}", workspace_path.display()))?;
+
+    let mut report = ValidationReport::default();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let Ok(message) = serde_json::from_str::<CargoCheckMessage>(line) else {
+            continue;
+        };
+        if message.reason != "compiler-message" {
+            continue;
+        }
+        let Some(diagnostic) = message.message else {
+            continue;
+        };
+        let entry = ValidationDiagnostic {
+            level: diagnostic.level.clone(),
+            message: diagnostic.rendered.unwrap_or(diagnostic.message),
+        };
+        match diagnostic.level.as_str() {
+            "error" => report.errors.push(entry),
+            "warning" => report.warnings.push(entry),
+            _ => {}
+        }
+    }
+
+    for diagnostic in &report.errors {
+        error!("{}", diagnostic.message);
+    }
+
+    Ok(report)
+}
+
+// Run the configured checker, and if it reports any error not already present in `baseline`
+// (the same checker's report against the pre-transform workspace), restore every file in
+// `original_contents` (path -> its pre-transform text) to undo the just-written rewrite.
+// Comparing against a baseline rather than treating any post-transform error as fatal matters
+// because C2Rust output routinely doesn't compile cleanly to begin with; without it, `--validate`
+// would roll back every run on such a workspace regardless of whether the transform helped, hurt,
+// or was a no-op. `original_contents` should be snapshotted by the caller immediately before its
+// final write-back loop, so a rollback leaves the workspace exactly as it found it.
+pub fn validate_and_maybe_rollback(
+    workspace_path: &Path,
+    config: &CheckerConfig,
+    original_contents: &HashMap<PathBuf, String>,
+    baseline: &ValidationReport,
+) -> Result<ValidationReport> {
+    let report = run_checker(workspace_path, config)?;
+    let baseline_messages: HashSet<&str> = baseline.errors.iter().map(|d| d.message.as_str()).collect();
+    let new_error_count = report
+        .errors
+        .iter()
+        .filter(|d| !baseline_messages.contains(d.message.as_str()))
+        .count();
+    if new_error_count > 0 {
+        warn!(
+            new_error_count,
+            total_error_count = report.errors.len(),
+            "Validation found new errors introduced by the transform; rolling back written files"
+        );
+        for (path, original) in original_contents {
+            fs::write(path, original)
+                .with_context(|| format!("failed to roll back {}", path.display()))?;
+        }
+    }
+    Ok(report)
+}
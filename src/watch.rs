@@ -0,0 +1,28 @@
+use anyhow::{anyhow, Result};
+use hayroll::{util, watch_core};
+use std::{env, path::Path, time::Duration};
+use tracing::error;
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        error!(usage = %format!("Usage: {} <workspace-path> [--poll-interval-ms=500]", args[0]));
+        std::process::exit(1);
+    }
+
+    util::init_logging();
+
+    let workspace_path = Path::new(&args[1]);
+    let poll_interval_ms = match args
+        .iter()
+        .skip(2)
+        .find_map(|arg| arg.strip_prefix("--poll-interval-ms="))
+    {
+        Some(value) => value
+            .parse::<u64>()
+            .map_err(|_| anyhow!("--poll-interval-ms expects an integer, got {value}"))?,
+        None => 500,
+    };
+
+    watch_core::run(workspace_path, Duration::from_millis(poll_interval_ms))
+}
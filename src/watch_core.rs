@@ -0,0 +1,100 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use ide_db::base_db::{SourceDatabase, SourceDatabaseFileInputExt};
+use ide_db::EditionedFileId;
+use load_cargo;
+use project_model::CargoConfig;
+use tracing::info;
+use vfs::{FileId, Vfs};
+
+use crate::hayroll_ds::{ExtractionActorHandle, ExtractionProgress};
+use crate::util::collect_syntax_roots_from_db;
+
+fn file_mtime(vfs: &Vfs, file_id: FileId) -> Option<SystemTime> {
+    let path = vfs.file_path(file_id);
+    let path = path.as_path()?;
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+fn log_progress(progress: &ExtractionProgress, actor: &ExtractionActorHandle) {
+    match progress {
+        ExtractionProgress::Started(file_id) => {
+            info!(?file_id, "re-extracting changed file");
+        }
+        ExtractionProgress::Completed(file_id) => {
+            let clusters = actor.db.lock().unwrap().map.len();
+            info!(?file_id, clusters, "re-extraction complete");
+        }
+        ExtractionProgress::Cancelled(file_id) => {
+            info!(?file_id, "re-extraction superseded by a newer edit");
+        }
+    }
+}
+
+// The real caller `ExtractionActorHandle` was missing: loads the workspace once, hands the actor
+// every file's initial syntax tree, then polls each file's on-disk mtime and feeds changed files
+// back in as `Restart` requests. This tree has no manifest to pull in an event-based watcher (e.g.
+// the `notify` crate), so a plain mtime poll is the change signal -- coarser than an fs-event
+// watch, but it keeps `actor.db`'s clusters live across edits instead of requiring a caller to
+// rerun `from_hayroll_macro_invs` over the whole workspace after each one. Runs until the process
+// is killed; there's no graceful-shutdown signal wired in for the same no-manifest reason.
+pub fn run(workspace_path: &Path, poll_interval: Duration) -> Result<()> {
+    let cargo_config = CargoConfig::default();
+    let load_cargo_config = load_cargo::LoadCargoConfig {
+        load_out_dirs_from_check: false,
+        with_proc_macro_server: load_cargo::ProcMacroServerChoice::None,
+        prefill_caches: false,
+    };
+
+    let (mut db, vfs, _proc_macro) =
+        load_cargo::load_workspace_at(workspace_path, &cargo_config, &load_cargo_config, &|_| {})?;
+
+    let syntax_roots = collect_syntax_roots_from_db(&db);
+    let actor = ExtractionActorHandle::spawn();
+
+    let mut mtimes: HashMap<FileId, SystemTime> = HashMap::new();
+    for (file_id, root) in syntax_roots.into_iter() {
+        if let Some(mtime) = file_mtime(&vfs, file_id) {
+            mtimes.insert(file_id, mtime);
+        }
+        actor.restart(file_id, root);
+    }
+
+    info!(files = mtimes.len(), "watching workspace for changes");
+
+    loop {
+        for progress in actor.progress.try_iter() {
+            log_progress(&progress, &actor);
+        }
+
+        let known_files: Vec<FileId> = mtimes.keys().copied().collect();
+        for file_id in known_files {
+            let Some(mtime) = file_mtime(&vfs, file_id) else {
+                continue;
+            };
+            if mtime <= mtimes[&file_id] {
+                continue;
+            }
+            mtimes.insert(file_id, mtime);
+
+            let path = vfs.file_path(file_id);
+            let Some(path) = path.as_path() else {
+                continue;
+            };
+            let Ok(text) = fs::read_to_string(path) else {
+                continue;
+            };
+            db.set_file_text(file_id, &text);
+            let root = db.parse(EditionedFileId::current_edition(file_id)).tree();
+            actor.restart(file_id, root);
+        }
+
+        std::thread::sleep(poll_interval);
+    }
+}